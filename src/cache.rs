@@ -0,0 +1,301 @@
+use std::{
+    sync::{Arc, RwLock},
+    time::{Duration, Instant, SystemTime},
+};
+
+use anyhow::Result;
+use tokio::sync::{Mutex, broadcast};
+use tracing::warn;
+
+use mulike::{BiliLiveClient, CaptainEntry, CrawlOptions, CrawlReport, RosterDiff, diff_rosters};
+
+use crate::store::Store;
+
+#[derive(Debug)]
+pub struct Snapshot {
+    pub entries: Vec<CaptainEntry>,
+    pub fetched_at: SystemTime,
+    pub report: CrawlReport,
+}
+
+impl Snapshot {
+    pub fn new(entries: Vec<CaptainEntry>, fetched_at: SystemTime) -> Self {
+        Self {
+            entries,
+            fetched_at,
+            report: CrawlReport::default(),
+        }
+    }
+
+    fn age(&self) -> Duration {
+        self.fetched_at.elapsed().unwrap_or_default()
+    }
+
+    /// Whether both hold the same people, in the same order, with the same names and tiers.
+    fn same_roster(&self, other: &Self) -> bool {
+        self.entries.len() == other.entries.len()
+            && self.entries.iter().zip(&other.entries).all(|(a, b)| {
+                a.uid == b.uid && a.username == b.username && a.guard_level == b.guard_level
+            })
+    }
+}
+
+/// How long a purchase seen live is kept while crawls don't list it yet.
+const LIVE_GRACE: Duration = Duration::from_secs(15 * 60);
+
+/// A crawl that found a different roster than the one before it.
+#[derive(Debug)]
+pub struct RosterUpdate {
+    pub roomid: u32,
+    pub at: SystemTime,
+    pub diff: RosterDiff,
+}
+
+/// Caches the roster of one room.
+///
+/// A snapshot younger than `ttl` is served as is. Up to `stale` past that it
+/// is still served, while a refresh runs in the background. Older than that,
+/// callers wait for a fresh crawl. Only one crawl runs at a time; everyone
+/// who asks during a crawl waits for that crawl instead of starting another.
+///
+/// When a poller keeps the cache warm, any snapshot is served immediately
+/// regardless of its age, and requests only crawl if there is none yet.
+///
+/// If a crawl fails, the last snapshot is served no matter how old it is.
+/// With a [`Store`], every crawl is recorded and the cache can be seeded from
+/// the last recorded one after a restart.
+///
+/// Every crawl that changes the roster is announced through [`Self::subscribe`].
+///
+/// Guard purchases seen live are merged in right away. Since the topList API
+/// lags behind, they are kept on top of crawls until a crawl lists them or
+/// [`LIVE_GRACE`] passes.
+#[derive(Debug)]
+pub struct RosterCache {
+    roomid: u32,
+    ruid: u64,
+    client: BiliLiveClient,
+    crawl: CrawlOptions,
+    ttl: Duration,
+    stale: Duration,
+    polled: bool,
+    store: Option<Store>,
+    current: RwLock<Option<Arc<Snapshot>>>,
+    refresh: Arc<Mutex<()>>,
+    updates: broadcast::Sender<Arc<RosterUpdate>>,
+    live: std::sync::Mutex<Vec<(Instant, CaptainEntry)>>,
+}
+
+impl RosterCache {
+    pub fn new(
+        roomid: u32,
+        ruid: u64,
+        client: BiliLiveClient,
+        crawl: CrawlOptions,
+        ttl: Duration,
+        stale: Duration,
+    ) -> Self {
+        Self {
+            roomid,
+            ruid,
+            client,
+            crawl,
+            ttl,
+            stale,
+            polled: false,
+            store: None,
+            current: RwLock::new(None),
+            refresh: Arc::new(Mutex::new(())),
+            updates: broadcast::channel(64).0,
+            live: std::sync::Mutex::new(vec![]),
+        }
+    }
+
+    /// Marks the cache as refreshed by a poller, see [`crate::poller`].
+    pub fn polled(mut self) -> Self {
+        self.polled = true;
+        self
+    }
+
+    pub fn with_store(mut self, store: Store) -> Self {
+        self.store = Some(store);
+        self
+    }
+
+    /// Loads the last recorded snapshot, if nothing was crawled yet.
+    pub async fn seed(&self) -> Result<()> {
+        let Some(store) = &self.store else {
+            return Ok(());
+        };
+
+        if let Some(snapshot) = store.latest(self.roomid, None).await? {
+            self.current
+                .write()
+                .unwrap()
+                .get_or_insert_with(|| Arc::new(snapshot));
+        }
+
+        Ok(())
+    }
+
+    pub fn roomid(&self) -> u32 {
+        self.roomid
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Arc<RosterUpdate>> {
+        self.updates.subscribe()
+    }
+
+    fn current(&self) -> Option<Arc<Snapshot>> {
+        self.current.read().unwrap().clone()
+    }
+
+    pub async fn get(self: &Arc<Self>) -> Result<Arc<Snapshot>> {
+        if let Some(snapshot) = self.current() {
+            let age = snapshot.age();
+
+            if self.polled || age < self.ttl {
+                return Ok(snapshot);
+            }
+
+            if age < self.ttl + self.stale {
+                // if the lock is taken a refresh is already on its way
+                if let Ok(guard) = self.refresh.clone().try_lock_owned() {
+                    let cache = self.clone();
+                    tokio::spawn(async move {
+                        let _guard = guard;
+                        if let Err(e) = cache.fetch().await {
+                            warn!("Background refresh of room {} failed: {e}", cache.roomid);
+                        }
+                    });
+                }
+
+                return Ok(snapshot);
+            }
+        }
+
+        let _guard = self.refresh.lock().await;
+
+        // whoever held the lock before us may have just refreshed it
+        if let Some(snapshot) = self.current()
+            && snapshot.age() < self.ttl
+        {
+            return Ok(snapshot);
+        }
+
+        match self.fetch().await {
+            Ok(snapshot) => Ok(snapshot),
+            Err(e) => match self.current() {
+                Some(snapshot) => {
+                    warn!(
+                        "Refreshing room {} failed, serving the roster from {:?}: {e}",
+                        self.roomid, snapshot.fetched_at
+                    );
+                    Ok(snapshot)
+                }
+                None => Err(e),
+            },
+        }
+    }
+
+    /// Crawls unconditionally, still sharing the crawl with concurrent callers.
+    pub async fn refresh(&self) -> Result<Arc<Snapshot>> {
+        let _guard = self.refresh.lock().await;
+        self.fetch().await
+    }
+
+    /// Merges a guard purchase seen live into the current roster.
+    pub fn merge_purchase(&self, entry: CaptainEntry) {
+        self.live
+            .lock()
+            .unwrap()
+            .push((Instant::now(), entry.clone()));
+
+        let mut current = self.current.write().unwrap();
+
+        // without a roster yet, the first crawl picks the purchase up
+        let Some(previous) = current.clone() else {
+            return;
+        };
+
+        let mut entries = previous.entries.clone();
+
+        match entries.iter_mut().find(|e| e.uid == entry.uid) {
+            Some(e) if u8::from(entry.guard_level) < u8::from(e.guard_level) => {
+                e.guard_level = entry.guard_level;
+            }
+            Some(_) => return,
+            None => entries.push(entry),
+        }
+
+        let snapshot = Arc::new(Snapshot {
+            report: previous.report.clone(),
+            ..Snapshot::new(entries, previous.fetched_at)
+        });
+        *current = Some(snapshot.clone());
+        drop(current);
+
+        self.announce(&previous, &snapshot);
+    }
+
+    /// Adds the live purchases a crawl doesn't list yet to its `entries`.
+    fn reconcile(&self, entries: &mut Vec<CaptainEntry>) {
+        let mut live = self.live.lock().unwrap();
+
+        live.retain(|(seen, purchase)| {
+            let listed = entries.iter().any(|e| {
+                e.uid == purchase.uid && u8::from(e.guard_level) <= u8::from(purchase.guard_level)
+            });
+
+            !listed && seen.elapsed() < LIVE_GRACE
+        });
+
+        for (_, purchase) in live.iter() {
+            match entries.iter_mut().find(|e| e.uid == purchase.uid) {
+                Some(e) => e.guard_level = purchase.guard_level,
+                None => entries.push(purchase.clone()),
+            }
+        }
+    }
+
+    fn announce(&self, previous: &Snapshot, snapshot: &Snapshot) {
+        let diff = diff_rosters(&previous.entries, &snapshot.entries);
+
+        if !diff.is_empty() {
+            // nobody listening is fine
+            let _ = self.updates.send(Arc::new(RosterUpdate {
+                roomid: self.roomid,
+                at: SystemTime::now(),
+                diff,
+            }));
+        }
+    }
+
+    async fn fetch(&self) -> Result<Arc<Snapshot>> {
+        let mut crawl = self
+            .client
+            .guard_list(self.roomid, self.ruid, &self.crawl)
+            .await?;
+        self.reconcile(&mut crawl.entries);
+
+        let snapshot = Arc::new(Snapshot {
+            report: crawl.report,
+            ..Snapshot::new(crawl.entries, SystemTime::now())
+        });
+        let previous = self.current.write().unwrap().replace(snapshot.clone());
+
+        if let Some(previous) = &previous {
+            self.announce(previous, &snapshot);
+        }
+
+        if let Some(store) = &self.store {
+            let unchanged = previous.is_some_and(|p| p.same_roster(&snapshot));
+
+            if let Err(e) = store.save(self.roomid, snapshot.clone(), unchanged).await {
+                warn!("Failed to store the roster of room {}: {e}", self.roomid);
+            }
+        }
+
+        Ok(snapshot)
+    }
+}
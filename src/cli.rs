@@ -0,0 +1,208 @@
+//! Command line interface. Besides serving, the rosters can be read once
+//! for scripts, which then exit like `grep` does: 0 on a hit, 1 on a miss
+//! or a difference, and 2 on an error.
+
+use std::{
+    path::{Path, PathBuf},
+    process::ExitCode,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Result};
+use clap::{Parser, Subcommand, ValueEnum};
+use mulike::{BiliLiveClient, CaptainEntry, diff_rosters};
+use serde::Deserialize;
+
+use crate::{
+    config::Config,
+    export::Delimited,
+    room::RoomConfig,
+    server::{CheckResponse, ListResponse, checked_guard},
+    store::Store,
+};
+
+#[derive(Debug, Parser)]
+#[command(version, about)]
+pub struct Cli {
+    /// Serves when left out.
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Serves the rosters of the configured rooms.
+    Serve,
+    /// Crawls a roster once and prints it.
+    Dump {
+        #[command(flatten)]
+        room: RoomArg,
+        #[arg(long, value_enum, default_value_t = DumpFormat::Txt)]
+        format: DumpFormat,
+        /// Prefix CSV/TSV output with a UTF-8 byte order mark.
+        #[arg(long)]
+        bom: bool,
+    },
+    /// Prints the changes between two rosters saved with `dump --format json`.
+    Diff { from: PathBuf, to: PathBuf },
+    /// Checks whether someone is a guard right now.
+    Check {
+        #[command(flatten)]
+        room: RoomArg,
+        #[arg(long, required_unless_present = "username")]
+        uid: Option<u64>,
+        /// Matched exactly.
+        #[arg(long)]
+        username: Option<String>,
+    },
+}
+
+#[derive(Debug, clap::Args)]
+pub struct RoomArg {
+    /// A configured alias or room ID, or any room written like in `ROOMS`.
+    /// Defaults to the first configured room.
+    #[arg(long)]
+    room: Option<String>,
+}
+
+impl RoomArg {
+    fn config(&self, config: &Config) -> Result<RoomConfig> {
+        let Some(room) = &self.room else {
+            return config
+                .rooms
+                .first()
+                .cloned()
+                .context("No room configured, pass --room");
+        };
+
+        let configured = config.rooms.iter().find(|r| {
+            r.alias.as_deref() == Some(room.as_str())
+                || room.parse::<u32>().is_ok_and(|id| id == r.roomid)
+        });
+
+        match configured {
+            Some(configured) => Ok(configured.clone()),
+            None => room
+                .parse::<RoomConfig>()
+                .with_context(|| format!("Unknown room {room}")),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum DumpFormat {
+    Json,
+    Csv,
+    Tsv,
+    Txt,
+}
+
+/// What `dump --format json` wrote.
+#[derive(Debug, Deserialize)]
+struct SavedRoster {
+    list: Vec<CaptainEntry>,
+}
+
+fn read_roster(path: &Path) -> Result<Vec<CaptainEntry>> {
+    let text = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+    let saved: SavedRoster = serde_json::from_str(&text)
+        .with_context(|| format!("Failed to parse {}", path.display()))?;
+    Ok(saved.list)
+}
+
+pub async fn dump(
+    config: &Config,
+    client: &BiliLiveClient,
+    room: &RoomArg,
+    format: DumpFormat,
+    bom: bool,
+) -> Result<ExitCode> {
+    let info = room.config(config)?.resolve(client).await?;
+    let crawl = client
+        .guard_list(info.roomid, info.ruid, &config.crawl())
+        .await?;
+    let list = crawl.entries;
+
+    match format {
+        DumpFormat::Json => {
+            let response = ListResponse {
+                roomid: info.roomid,
+                total: list.len(),
+                fetched_at: SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
+                anomalies: crawl.report.anomalies,
+                list,
+            };
+            println!("{}", serde_json::to_string_pretty(&response)?);
+        }
+        DumpFormat::Csv => print!("{}", Delimited::Csv.render(&list, bom)),
+        DumpFormat::Tsv => print!("{}", Delimited::Tsv.render(&list, bom)),
+        DumpFormat::Txt => {
+            for entry in list {
+                println!("{}", entry.username);
+            }
+        }
+    }
+
+    Ok(ExitCode::SUCCESS)
+}
+
+/// Exits with 1 if the rosters differ.
+pub fn diff(from: &Path, to: &Path) -> Result<ExitCode> {
+    let diff = diff_rosters(&read_roster(from)?, &read_roster(to)?);
+    println!("{}", serde_json::to_string_pretty(&diff)?);
+
+    Ok(if diff.is_empty() {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    })
+}
+
+/// Prints what `/check` would answer and exits with 1 if they aren't a guard.
+pub async fn check(
+    config: &Config,
+    client: &BiliLiveClient,
+    room: &RoomArg,
+    uid: Option<u64>,
+    username: Option<&str>,
+) -> Result<ExitCode> {
+    let info = room.config(config)?.resolve(client).await?;
+    let crawl = client
+        .guard_list(info.roomid, info.ruid, &config.crawl())
+        .await?;
+
+    let entry = crawl.entries.iter().find(|e| {
+        uid.is_none_or(|uid| e.uid == uid) && username.is_none_or(|username| e.username == username)
+    });
+
+    let guard = match entry {
+        Some(entry) => {
+            // the recorded history, if any, tells how long they have been a guard
+            let retention = Duration::from_secs(config.database.retention_days * 24 * 60 * 60);
+            let store = config
+                .database
+                .path
+                .as_ref()
+                .map(|path| Store::open(path, retention))
+                .transpose()?;
+            Some(checked_guard(info.roomid, entry, store.as_ref()).await?)
+        }
+        None => None,
+    };
+
+    let exit = if guard.is_some() {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    };
+
+    let response = CheckResponse {
+        roomid: info.roomid,
+        is_guard: guard.is_some(),
+        guard,
+    };
+    println!("{}", serde_json::to_string_pretty(&response)?);
+
+    Ok(exit)
+}
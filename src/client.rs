@@ -0,0 +1,566 @@
+use std::{collections::HashSet, time::Duration};
+
+use anyhow::Result;
+use futures_util::{Stream, StreamExt, TryStreamExt, stream};
+use reqwest::{StatusCode, header};
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use crate::{CaptainEntry, retry::RetryPolicy, upstream::UpstreamError};
+
+/// What [`BiliLiveClient::new`] identifies as. The API turns away clients
+/// that don't look like a browser.
+pub const USER_AGENT: &str =
+    "Mozilla/5.0 (X11; Linux x86_64; rv:140.0) Gecko/20100101 Firefox/140.0";
+
+/// How many topList pages are fetched at once.
+const PAGE_CONCURRENCY: usize = 4;
+
+#[derive(Debug, Deserialize)]
+struct Captain {
+    code: i32,
+    #[serde(default)]
+    message: String,
+    data: Option<CaptainData>,
+}
+
+#[derive(Debug, Deserialize)]
+struct CaptainData {
+    info: CaptainDataInfo,
+    list: Vec<CaptainEntry>,
+    top3: Option<Vec<CaptainEntry>>,
+}
+
+#[derive(Debug, Deserialize)]
+struct CaptainDataInfo {
+    /// Number of guards.
+    #[serde(default)]
+    num: u32,
+    /// Number of pages.
+    page: i32,
+}
+
+#[derive(Debug, Deserialize)]
+struct RoomInit {
+    code: i32,
+    #[serde(default)]
+    message: String,
+    data: Option<RoomInitData>,
+}
+
+#[derive(Debug, Deserialize)]
+struct RoomInitData {
+    room_id: u32,
+    short_id: u32,
+    uid: u64,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct RoomInfo {
+    pub roomid: u32,
+    /// `None` when the room has no short ID.
+    pub short_id: Option<u32>,
+    /// The anchor's UID, which the guard list is looked up by.
+    pub ruid: u64,
+}
+
+/// One page of the guard list.
+#[derive(Debug, Clone)]
+pub struct GuardPage {
+    pub page: i32,
+    /// Number of pages, as reported along with this one.
+    pub pages: i32,
+    /// Number of guards, as reported along with this one.
+    pub num: u32,
+    /// The guards on this page. The first page starts with the top three.
+    pub entries: Vec<CaptainEntry>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct CrawlOptions {
+    pub retry: RetryPolicy,
+    /// Pages past this are never fetched, whatever upstream reports.
+    pub max_pages: i32,
+    /// How often to crawl again when the first and the last page disagree
+    /// about the roster size; 0 accepts the first crawl as is.
+    pub recrawls: u32,
+}
+
+/// Something off with the pages upstream returned during a crawl.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Anomaly {
+    /// More pages were reported than [`CrawlOptions::max_pages`].
+    PageCapReached { reported: i32, cap: i32 },
+    /// The roster changed while crawling: the last page reported another
+    /// guard or page count than the first.
+    InconsistentPages {
+        first_num: u32,
+        first_pages: i32,
+        last_num: u32,
+        last_pages: i32,
+    },
+    /// The crawl was repeated because of inconsistent pages.
+    Recrawled { times: u32 },
+    /// Entries listed on more than one page, dropped.
+    Duplicates { count: usize },
+    /// The first page reported `reported` guards, the crawl found `found`.
+    CountMismatch { reported: u32, found: usize },
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct CrawlReport {
+    pub anomalies: Vec<Anomaly>,
+}
+
+/// The whole guard list of a room.
+#[derive(Debug)]
+pub struct Crawl {
+    pub entries: Vec<CaptainEntry>,
+    pub report: CrawlReport,
+}
+
+impl Crawl {
+    /// Whether the first and the last page agreed about the roster size.
+    fn is_consistent(&self) -> bool {
+        !self
+            .report
+            .anomalies
+            .iter()
+            .any(|a| matches!(a, Anomaly::InconsistentPages { .. }))
+    }
+}
+
+/// Reads rooms and their guard lists from the Bilibili live API.
+#[derive(Debug, Clone)]
+pub struct BiliLiveClient {
+    http: reqwest::Client,
+}
+
+impl Default for BiliLiveClient {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl BiliLiveClient {
+    pub fn new() -> Self {
+        let http = reqwest::Client::builder()
+            .user_agent(USER_AGENT)
+            .build()
+            .expect("Failed to build the HTTP client");
+
+        Self::with_http(http)
+    }
+
+    /// Sends every request with `http`, e.g. to set timeouts. It should send
+    /// a browser's user agent, like [`USER_AGENT`].
+    pub fn with_http(http: reqwest::Client) -> Self {
+        Self { http }
+    }
+
+    pub fn http(&self) -> &reqwest::Client {
+        &self.http
+    }
+
+    /// Resolves a room ID, short or long, to the canonical room ID and its anchor.
+    pub async fn room_info(&self, roomid: u32) -> Result<RoomInfo> {
+        let init = self
+            .http
+            .get("https://api.live.bilibili.com/room/v1/Room/room_init")
+            .query(&[("id", roomid.to_string())])
+            .send()
+            .await?
+            .error_for_status()?
+            .json::<RoomInit>()
+            .await?;
+
+        if init.code != 0 {
+            return Err(UpstreamError::new(init.code, init.message).into());
+        }
+
+        let data = init
+            .data
+            .ok_or_else(|| anyhow::anyhow!("room_init of room {roomid} has no data"))?;
+
+        Ok(RoomInfo {
+            roomid: data.room_id,
+            short_id: (data.short_id != 0).then_some(data.short_id),
+            ruid: data.uid,
+        })
+    }
+
+    /// Fetches one page of the guard list, once.
+    pub async fn guard_page(&self, roomid: u32, ruid: u64, page: i32) -> Result<GuardPage> {
+        let resp = self
+            .http
+            .get("https://api.live.bilibili.com/xlive/app-room/v2/guardTab/topList")
+            .query(&[
+                ("roomid", roomid.to_string()),
+                ("ruid", ruid.to_string()),
+                ("page", page.to_string()),
+                ("page_size", "30".to_string()),
+            ])
+            .send()
+            .await?;
+
+        let status = resp.status();
+
+        if status == StatusCode::PRECONDITION_FAILED || status == StatusCode::TOO_MANY_REQUESTS {
+            let retry_after = resp
+                .headers()
+                .get(header::RETRY_AFTER)
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.trim().parse().ok())
+                .map(Duration::from_secs);
+
+            return Err(UpstreamError::RateLimited {
+                message: format!("HTTP {status}"),
+                retry_after,
+            }
+            .into());
+        }
+
+        let c = resp.error_for_status()?.json::<Captain>().await?;
+
+        if c.code != 0 {
+            return Err(UpstreamError::new(c.code, c.message).into());
+        }
+
+        let data = c
+            .data
+            .ok_or_else(|| anyhow::anyhow!("topList of room {roomid} has no data"))?;
+
+        Ok(GuardPage {
+            page,
+            pages: data.info.page,
+            num: data.info.num,
+            entries: data.top3.into_iter().flatten().chain(data.list).collect(),
+        })
+    }
+
+    async fn fetch_page(
+        &self,
+        roomid: u32,
+        ruid: u64,
+        page: i32,
+        retry: &RetryPolicy,
+    ) -> Result<GuardPage> {
+        retry
+            .run(&format!("Fetching page {page} of room {roomid}"), || {
+                self.guard_page(roomid, ruid, page)
+            })
+            .await
+    }
+
+    /// The guard list page by page, as many pages as the first one reports.
+    ///
+    /// Unlike [`Self::guard_list`], pages are fetched one after another and
+    /// taken as they come: guards moving between pages meanwhile may show up
+    /// twice or not at all.
+    pub fn guard_pages(
+        &self,
+        roomid: u32,
+        ruid: u64,
+        retry: RetryPolicy,
+    ) -> impl Stream<Item = Result<GuardPage>> + '_ {
+        stream::try_unfold((1, 1), move |(page, pages)| async move {
+            if page > pages {
+                return Ok(None);
+            }
+
+            let data = self.fetch_page(roomid, ruid, page, &retry).await?;
+            let pages = if page == 1 { data.pages } else { pages };
+
+            anyhow::Ok(Some((data, (page + 1, pages))))
+        })
+    }
+
+    /// Crawls the whole guard list, crawling again as `options` say when it
+    /// changes underway.
+    pub async fn guard_list(
+        &self,
+        roomid: u32,
+        ruid: u64,
+        options: &CrawlOptions,
+    ) -> Result<Crawl> {
+        recrawl(roomid, options.recrawls, || {
+            self.crawl(roomid, ruid, options)
+        })
+        .await
+    }
+
+    /// Crawls every page once.
+    async fn crawl(&self, roomid: u32, ruid: u64, options: &CrawlOptions) -> Result<Crawl> {
+        let fetch = |page: i32| self.fetch_page(roomid, ruid, page, &options.retry);
+
+        let first = fetch(1).await?;
+        let pages = first.pages.min(options.max_pages);
+
+        // the first page tells how many there are, the rest can be fetched at once
+        let rest = stream::iter(2..=pages)
+            .map(fetch)
+            .buffered(PAGE_CONCURRENCY)
+            .try_collect::<Vec<_>>()
+            .await?;
+
+        Ok(assemble(first, rest, options.max_pages))
+    }
+}
+
+/// Runs `crawl_once` until the pages it gets agree about the roster size, at
+/// most `recrawls` times more.
+async fn recrawl<F>(roomid: u32, recrawls: u32, mut crawl_once: impl FnMut() -> F) -> Result<Crawl>
+where
+    F: Future<Output = Result<Crawl>>,
+{
+    let mut times = 0;
+
+    loop {
+        let mut crawl = crawl_once().await?;
+
+        if crawl.is_consistent() || times >= recrawls {
+            if times > 0 {
+                crawl.report.anomalies.push(Anomaly::Recrawled { times });
+            }
+
+            if !crawl.report.anomalies.is_empty() {
+                warn!(
+                    "Crawl of room {roomid} had anomalies: {:?}",
+                    crawl.report.anomalies
+                );
+            }
+
+            return Ok(crawl);
+        }
+
+        times += 1;
+        warn!("Pages of room {roomid} changed while crawling, crawling again");
+    }
+}
+
+/// Puts the pages of one crawl together: `first`, then the `rest` fetched
+/// after it, of which there should be no more than `max_pages` in all.
+fn assemble(first: GuardPage, rest: Vec<GuardPage>, max_pages: i32) -> Crawl {
+    let mut report = CrawlReport::default();
+
+    if first.pages < 1 {
+        return Crawl {
+            entries: vec![],
+            report,
+        };
+    }
+
+    if first.pages > max_pages {
+        report.anomalies.push(Anomaly::PageCapReached {
+            reported: first.pages,
+            cap: max_pages,
+        });
+    }
+
+    let (last_num, last_pages) = rest
+        .last()
+        .map_or((first.num, first.pages), |last| (last.num, last.pages));
+
+    if last_num != first.num || last_pages != first.pages {
+        report.anomalies.push(Anomaly::InconsistentPages {
+            first_num: first.num,
+            first_pages: first.pages,
+            last_num,
+            last_pages,
+        });
+    }
+
+    let reported_num = first.num;
+
+    let entries = first
+        .entries
+        .into_iter()
+        .chain(rest.into_iter().flat_map(|page| page.entries));
+
+    // entries move between pages while we fetch, so some show up twice
+    let mut seen = HashSet::new();
+    let mut duplicates = 0;
+
+    let entries = entries
+        .filter(|e| {
+            let new = seen.insert(e.uid);
+            duplicates += usize::from(!new);
+            new
+        })
+        .collect::<Vec<_>>();
+
+    if duplicates > 0 {
+        report
+            .anomalies
+            .push(Anomaly::Duplicates { count: duplicates });
+    }
+
+    if reported_num != 0 && entries.len() != reported_num as usize {
+        report.anomalies.push(Anomaly::CountMismatch {
+            reported: reported_num,
+            found: entries.len(),
+        });
+    }
+
+    Crawl { entries, report }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+
+    use crate::GuardLevel;
+
+    use super::*;
+
+    fn entry(uid: u64) -> CaptainEntry {
+        CaptainEntry {
+            uid,
+            username: uid.to_string(),
+            rank: 0,
+            guard_level: GuardLevel::Captain,
+            accompany: 0,
+            face: String::new(),
+            medal_info: None,
+        }
+    }
+
+    fn page(page: i32, pages: i32, num: u32, uids: impl IntoIterator<Item = u64>) -> GuardPage {
+        GuardPage {
+            page,
+            pages,
+            num,
+            entries: uids.into_iter().map(entry).collect(),
+        }
+    }
+
+    fn uids(crawl: &Crawl) -> Vec<u64> {
+        crawl.entries.iter().map(|e| e.uid).collect()
+    }
+
+    #[test]
+    fn assembles_consistent_pages() {
+        let crawl = assemble(page(1, 2, 4, [1, 2]), vec![page(2, 2, 4, [3, 4])], 10);
+
+        assert_eq!(uids(&crawl), [1, 2, 3, 4]);
+        assert_eq!(crawl.report, CrawlReport::default());
+        assert!(crawl.is_consistent());
+    }
+
+    #[test]
+    fn empty_rosters_have_no_entries() {
+        let crawl = assemble(page(1, 0, 0, []), vec![], 10);
+
+        assert!(crawl.entries.is_empty());
+        assert_eq!(crawl.report, CrawlReport::default());
+    }
+
+    #[test]
+    fn reports_page_cap() {
+        let crawl = assemble(page(1, 5, 4, [1, 2]), vec![page(2, 5, 4, [3, 4])], 2);
+
+        assert_eq!(
+            crawl.report.anomalies,
+            [Anomaly::PageCapReached {
+                reported: 5,
+                cap: 2
+            }]
+        );
+    }
+
+    #[test]
+    fn reports_inconsistent_pages() {
+        let crawl = assemble(page(1, 2, 4, [1, 2]), vec![page(2, 3, 5, [3, 4])], 10);
+
+        assert_eq!(
+            crawl.report.anomalies,
+            [Anomaly::InconsistentPages {
+                first_num: 4,
+                first_pages: 2,
+                last_num: 5,
+                last_pages: 3,
+            },]
+        );
+        assert!(!crawl.is_consistent());
+    }
+
+    #[test]
+    fn drops_and_reports_duplicates() {
+        let crawl = assemble(
+            page(1, 3, 4, [1, 2]),
+            vec![page(2, 3, 4, [2, 3]), page(3, 3, 4, [3, 4])],
+            10,
+        );
+
+        assert_eq!(uids(&crawl), [1, 2, 3, 4]);
+        assert_eq!(crawl.report.anomalies, [Anomaly::Duplicates { count: 2 }]);
+    }
+
+    #[test]
+    fn reports_count_mismatch() {
+        let crawl = assemble(page(1, 2, 5, [1, 2]), vec![page(2, 2, 5, [3])], 10);
+
+        assert_eq!(
+            crawl.report.anomalies,
+            [Anomaly::CountMismatch {
+                reported: 5,
+                found: 3
+            }]
+        );
+    }
+
+    /// Runs [`recrawl`] over canned crawls, returning the result and how
+    /// many crawls it took.
+    async fn recrawled(recrawls: u32, crawls: Vec<(GuardPage, GuardPage)>) -> (Crawl, usize) {
+        let mut crawls = crawls.into_iter().collect::<VecDeque<_>>();
+        let mut taken = 0;
+
+        let crawl = recrawl(1, recrawls, || {
+            taken += 1;
+            let (first, last) = crawls.pop_front().expect("crawled too often");
+            async move { Ok(assemble(first, vec![last], 10)) }
+        })
+        .await
+        .unwrap();
+
+        (crawl, taken)
+    }
+
+    #[tokio::test]
+    async fn recrawls_until_consistent() {
+        let changed = (page(1, 2, 4, [1, 2]), page(2, 2, 5, [3, 4, 5]));
+        let settled = (page(1, 2, 4, [1, 2]), page(2, 2, 4, [3, 4]));
+
+        let (crawl, taken) = recrawled(3, vec![changed, settled]).await;
+
+        assert_eq!(taken, 2);
+        assert_eq!(uids(&crawl), [1, 2, 3, 4]);
+        assert_eq!(crawl.report.anomalies, [Anomaly::Recrawled { times: 1 }]);
+    }
+
+    #[tokio::test]
+    async fn gives_up_recrawling() {
+        let changed = || (page(1, 2, 4, [1, 2]), page(2, 2, 5, [3, 4, 5]));
+
+        let (crawl, taken) = recrawled(1, vec![changed(), changed()]).await;
+
+        assert_eq!(taken, 2);
+        assert_eq!(
+            crawl.report.anomalies,
+            [
+                Anomaly::InconsistentPages {
+                    first_num: 4,
+                    first_pages: 2,
+                    last_num: 5,
+                    last_pages: 2,
+                },
+                Anomaly::CountMismatch {
+                    reported: 4,
+                    found: 5
+                },
+                Anomaly::Recrawled { times: 1 },
+            ]
+        );
+    }
+}
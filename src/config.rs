@@ -0,0 +1,514 @@
+//! Settings, read from a TOML file and overridden by environment variables.
+//!
+//! The file is `mulike.toml`, or whatever `MULIKE_CONFIG` points to. Every
+//! setting has an environment variable, named in its doc comment, which
+//! wins over the file. Without a file, the environment alone is used.
+
+use std::{
+    fmt,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+
+use serde::Deserialize;
+
+use mulike::{CrawlOptions, retry::RetryPolicy};
+
+use crate::{room::RoomConfig, server::TokenConfig, webhook::WebhookConfig};
+
+const DEFAULT_PATH: &str = "mulike.toml";
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Addresses to serve on. `LOCAL_URL`, comma-separated.
+    pub listen: Vec<String>,
+    /// `ROOMS` as `[alias=]roomid[:ruid]`, comma-separated, or a single
+    /// room from `ROOMID` and `RUID`.
+    pub rooms: Vec<RoomConfig>,
+    pub cache: CacheConfig,
+    pub poll: PollConfig,
+    pub upstream: UpstreamConfig,
+    pub database: DatabaseConfig,
+    pub webhooks: WebhooksConfig,
+    pub auth: AuthConfig,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CacheConfig {
+    /// `CACHE_TTL_SECS`
+    pub ttl_secs: u64,
+    /// `CACHE_STALE_SECS`
+    pub stale_secs: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PollConfig {
+    /// `POLL_INTERVAL_SECS`; 0 disables the poller, leaving refreshes to
+    /// incoming requests.
+    pub interval_secs: u64,
+    /// `POLL_JITTER_SECS`
+    pub jitter_secs: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UpstreamConfig {
+    /// `UPSTREAM_RETRIES`
+    pub retries: u32,
+    /// `UPSTREAM_BACKOFF_MS`
+    pub backoff_ms: u64,
+    /// `UPSTREAM_MAX_BACKOFF_MS`
+    pub max_backoff_ms: u64,
+    /// `UPSTREAM_CONNECT_TIMEOUT_SECS`
+    pub connect_timeout_secs: u64,
+    /// `UPSTREAM_READ_TIMEOUT_SECS`
+    pub read_timeout_secs: u64,
+    /// `UPSTREAM_MAX_PAGES`
+    pub max_pages: i32,
+    /// `UPSTREAM_RECRAWLS`
+    pub recrawls: u32,
+    /// `LIVE_EVENTS`: listen to the live message stream for guard purchases.
+    pub live_events: bool,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DatabaseConfig {
+    /// `DATABASE_PATH`; snapshots are only kept when it is set.
+    pub path: Option<PathBuf>,
+    /// `SNAPSHOT_RETENTION_DAYS`
+    pub retention_days: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WebhooksConfig {
+    /// `WEBHOOK_URLS`, comma-separated.
+    pub urls: Vec<String>,
+    /// `WEBHOOK_SECRET`
+    pub secret: Option<String>,
+    /// `WEBHOOK_RETRIES`
+    pub retries: u32,
+    /// `WEBHOOK_BACKOFF_SECS`
+    pub backoff_secs: u64,
+    /// `WEBHOOK_MAX_BACKOFF_SECS`
+    pub max_backoff_secs: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    /// `TOKEN_SECRET`; membership tokens are only issued when it is set.
+    pub token_secret: Option<String>,
+    /// `TOKEN_API_KEY`, which `/token` callers send as a bearer token.
+    /// Required along with `token_secret`: only trusted services, which
+    /// know who they are talking to, may ask for someone's token.
+    pub token_api_key: Option<String>,
+    /// `TOKEN_TTL_SECS`
+    pub token_ttl_secs: u64,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            listen: vec![],
+            rooms: vec![],
+            cache: CacheConfig::default(),
+            poll: PollConfig::default(),
+            upstream: UpstreamConfig::default(),
+            database: DatabaseConfig {
+                path: None,
+                retention_days: 30,
+            },
+            webhooks: WebhooksConfig::default(),
+            auth: AuthConfig::default(),
+        }
+    }
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self {
+            ttl_secs: 60,
+            stale_secs: 300,
+        }
+    }
+}
+
+impl Default for PollConfig {
+    fn default() -> Self {
+        Self {
+            interval_secs: 60,
+            jitter_secs: 10,
+        }
+    }
+}
+
+impl Default for UpstreamConfig {
+    fn default() -> Self {
+        Self {
+            retries: 3,
+            backoff_ms: 500,
+            max_backoff_ms: 10_000,
+            connect_timeout_secs: 5,
+            read_timeout_secs: 10,
+            max_pages: 200,
+            recrawls: 1,
+            live_events: false,
+        }
+    }
+}
+
+impl Default for WebhooksConfig {
+    fn default() -> Self {
+        Self {
+            urls: vec![],
+            secret: None,
+            retries: 8,
+            backoff_secs: 10,
+            max_backoff_secs: 3600,
+        }
+    }
+}
+
+impl Default for AuthConfig {
+    fn default() -> Self {
+        Self {
+            token_secret: None,
+            token_api_key: None,
+            token_ttl_secs: 600,
+        }
+    }
+}
+
+/// Everything wrong with a configuration, so it can be fixed in one go.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigError(pub Vec<String>);
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Invalid configuration:")?;
+        for problem in &self.0 {
+            writeln!(f, "  - {problem}")?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// Sets `target` from the environment variable `key`, if it is set.
+fn env<T>(key: &str, target: &mut T, problems: &mut Vec<String>)
+where
+    T: FromStr,
+    T::Err: fmt::Display,
+{
+    if let Ok(value) = std::env::var(key) {
+        match value.trim().parse() {
+            Ok(value) => *target = value,
+            Err(e) => problems.push(format!("{key}: cannot parse `{value}`: {e}")),
+        }
+    }
+}
+
+fn env_list(key: &str) -> Option<Vec<String>> {
+    std::env::var(key).ok().map(|value| {
+        value
+            .split(',')
+            .map(str::trim)
+            .filter(|v| !v.is_empty())
+            .map(str::to_string)
+            .collect()
+    })
+}
+
+impl Config {
+    /// Reads the file named by `MULIKE_CONFIG`, or `mulike.toml` if it
+    /// exists, applies the environment on top and validates the result.
+    pub fn load() -> Result<Self, ConfigError> {
+        let (path, required) = Self::path();
+        Self::load_from(&path, required)
+    }
+
+    /// The file [`Self::load`] reads, and whether it has to exist.
+    pub fn path() -> (PathBuf, bool) {
+        match std::env::var("MULIKE_CONFIG") {
+            Ok(path) => (PathBuf::from(path), true),
+            Err(_) => (PathBuf::from(DEFAULT_PATH), false),
+        }
+    }
+
+    /// Like [`Self::load`], with the file at `path`. Without `required`,
+    /// a missing file counts as an empty one.
+    pub fn load_from(path: &Path, required: bool) -> Result<Self, ConfigError> {
+        let mut problems = vec![];
+
+        let mut config = match std::fs::read_to_string(path) {
+            Ok(text) => toml::from_str(&text).unwrap_or_else(|e| {
+                problems.push(format!("{}: {e}", path.display()));
+                Self::default()
+            }),
+            Err(e) if required || e.kind() != std::io::ErrorKind::NotFound => {
+                problems.push(format!("{}: {e}", path.display()));
+                Self::default()
+            }
+            Err(_) => Self::default(),
+        };
+
+        // checking the defaults in place of an unreadable file would only
+        // add problems that aren't there
+        let readable = problems.is_empty();
+
+        config.apply_env(&mut problems);
+        if readable {
+            config.validate(&mut problems);
+        }
+
+        if problems.is_empty() {
+            Ok(config)
+        } else {
+            Err(ConfigError(problems))
+        }
+    }
+
+    fn apply_env(&mut self, problems: &mut Vec<String>) {
+        if let Some(listen) = env_list("LOCAL_URL") {
+            self.listen = listen;
+        }
+
+        if let Some(rooms) = env_list("ROOMS") {
+            self.rooms = rooms
+                .iter()
+                .filter_map(|room| match room.parse() {
+                    Ok(room) => Some(room),
+                    Err(e) => {
+                        problems.push(format!("ROOMS: `{room}`: {e:#}"));
+                        None
+                    }
+                })
+                .collect();
+        } else if std::env::var("ROOMID").is_ok() {
+            let mut room = RoomConfig {
+                alias: None,
+                roomid: 0,
+                ruid: None,
+            };
+            env("ROOMID", &mut room.roomid, problems);
+
+            if std::env::var("RUID").is_ok() {
+                let mut ruid = 0;
+                env("RUID", &mut ruid, problems);
+                room.ruid = Some(ruid);
+            }
+
+            self.rooms = vec![room];
+        } else if std::env::var("RUID").is_ok() {
+            problems.push("RUID is set without ROOMID".to_string());
+        }
+
+        env("CACHE_TTL_SECS", &mut self.cache.ttl_secs, problems);
+        env("CACHE_STALE_SECS", &mut self.cache.stale_secs, problems);
+        env("POLL_INTERVAL_SECS", &mut self.poll.interval_secs, problems);
+        env("POLL_JITTER_SECS", &mut self.poll.jitter_secs, problems);
+
+        let upstream = &mut self.upstream;
+        env("UPSTREAM_RETRIES", &mut upstream.retries, problems);
+        env("UPSTREAM_BACKOFF_MS", &mut upstream.backoff_ms, problems);
+        env(
+            "UPSTREAM_MAX_BACKOFF_MS",
+            &mut upstream.max_backoff_ms,
+            problems,
+        );
+        env(
+            "UPSTREAM_CONNECT_TIMEOUT_SECS",
+            &mut upstream.connect_timeout_secs,
+            problems,
+        );
+        env(
+            "UPSTREAM_READ_TIMEOUT_SECS",
+            &mut upstream.read_timeout_secs,
+            problems,
+        );
+        env("UPSTREAM_MAX_PAGES", &mut upstream.max_pages, problems);
+        env("UPSTREAM_RECRAWLS", &mut upstream.recrawls, problems);
+        env("LIVE_EVENTS", &mut upstream.live_events, problems);
+
+        if let Ok(path) = std::env::var("DATABASE_PATH") {
+            self.database.path = Some(path.into());
+        }
+        env(
+            "SNAPSHOT_RETENTION_DAYS",
+            &mut self.database.retention_days,
+            problems,
+        );
+
+        let webhooks = &mut self.webhooks;
+        if let Some(urls) = env_list("WEBHOOK_URLS") {
+            webhooks.urls = urls;
+        }
+        if let Ok(secret) = std::env::var("WEBHOOK_SECRET") {
+            webhooks.secret = Some(secret);
+        }
+        env("WEBHOOK_RETRIES", &mut webhooks.retries, problems);
+        env("WEBHOOK_BACKOFF_SECS", &mut webhooks.backoff_secs, problems);
+        env(
+            "WEBHOOK_MAX_BACKOFF_SECS",
+            &mut webhooks.max_backoff_secs,
+            problems,
+        );
+
+        if let Ok(secret) = std::env::var("TOKEN_SECRET") {
+            self.auth.token_secret = Some(secret);
+        }
+        if let Ok(key) = std::env::var("TOKEN_API_KEY") {
+            self.auth.token_api_key = Some(key);
+        }
+        env("TOKEN_TTL_SECS", &mut self.auth.token_ttl_secs, problems);
+    }
+
+    /// What serving needs on top of what [`Self::load`] checks, which the
+    /// one-off commands don't.
+    pub fn validate_serve(&self) -> Result<(), ConfigError> {
+        let mut problems = vec![];
+
+        if self.listen.is_empty() {
+            problems.push("listen: no address to serve on, set it or LOCAL_URL".to_string());
+        }
+        if self.rooms.is_empty() {
+            problems.push("rooms: no room to serve, set it, ROOMS or ROOMID".to_string());
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(ConfigError(problems))
+        }
+    }
+
+    fn validate(&self, problems: &mut Vec<String>) {
+        for address in &self.listen {
+            let port = address
+                .rsplit_once(':')
+                .map(|(_, port)| port.parse::<u16>());
+            if !matches!(port, Some(Ok(_))) {
+                problems.push(format!("listen: `{address}` is not a host:port address"));
+            }
+        }
+
+        for (i, room) in self.rooms.iter().enumerate() {
+            if room.roomid == 0 {
+                problems.push(format!("rooms[{i}]: roomid must not be 0"));
+            }
+            if self.rooms[..i].iter().any(|r| r.roomid == room.roomid) {
+                problems.push(format!("rooms[{i}]: room {} is listed twice", room.roomid));
+            }
+            if let Some(alias) = &room.alias {
+                if alias.is_empty() {
+                    problems.push(format!("rooms[{i}]: alias must not be empty"));
+                }
+                if self.rooms[..i]
+                    .iter()
+                    .any(|r| r.alias.as_ref() == Some(alias))
+                {
+                    problems.push(format!("rooms[{i}]: alias `{alias}` is used twice"));
+                }
+            }
+        }
+
+        if self.cache.ttl_secs == 0 {
+            problems.push("cache.ttl_secs: must be positive".to_string());
+        }
+
+        let upstream = &self.upstream;
+        if upstream.backoff_ms > upstream.max_backoff_ms {
+            problems.push("upstream.backoff_ms: exceeds upstream.max_backoff_ms".to_string());
+        }
+        if upstream.connect_timeout_secs == 0 || upstream.read_timeout_secs == 0 {
+            problems.push("upstream: timeouts must be positive".to_string());
+        }
+        if upstream.max_pages < 1 {
+            problems.push("upstream.max_pages: must be at least 1".to_string());
+        }
+
+        if self.database.retention_days == 0 {
+            problems.push("database.retention_days: must be positive".to_string());
+        }
+
+        let webhooks = &self.webhooks;
+        if !webhooks.urls.is_empty() && self.database.path.is_none() {
+            problems.push(
+                "webhooks.urls: needs database.path (DATABASE_PATH) to keep its outbox".to_string(),
+            );
+        }
+        for url in &webhooks.urls {
+            match reqwest::Url::parse(url) {
+                Ok(url) if matches!(url.scheme(), "http" | "https") => {}
+                Ok(_) => problems.push(format!("webhooks.urls: `{url}` is not an HTTP URL")),
+                Err(e) => problems.push(format!("webhooks.urls: `{url}`: {e}")),
+            }
+        }
+        if webhooks.backoff_secs > webhooks.max_backoff_secs {
+            problems.push("webhooks.backoff_secs: exceeds webhooks.max_backoff_secs".to_string());
+        }
+
+        if let Some(secret) = &self.auth.token_secret
+            && secret.len() < 16
+        {
+            problems.push("auth.token_secret: must be at least 16 bytes".to_string());
+        }
+        if self.auth.token_secret.is_some() && self.auth.token_api_key.is_none() {
+            problems.push("auth.token_api_key: required along with auth.token_secret".to_string());
+        }
+        if let Some(key) = &self.auth.token_api_key
+            && key.len() < 16
+        {
+            problems.push("auth.token_api_key: must be at least 16 bytes".to_string());
+        }
+        if self.auth.token_ttl_secs == 0 {
+            problems.push("auth.token_ttl_secs: must be positive".to_string());
+        }
+    }
+
+    pub fn retry(&self) -> RetryPolicy {
+        RetryPolicy {
+            retries: self.upstream.retries,
+            base: Duration::from_millis(self.upstream.backoff_ms),
+            max: Duration::from_millis(self.upstream.max_backoff_ms),
+        }
+    }
+
+    pub fn crawl(&self) -> CrawlOptions {
+        CrawlOptions {
+            retry: self.retry(),
+            max_pages: self.upstream.max_pages,
+            recrawls: self.upstream.recrawls,
+        }
+    }
+
+    pub fn webhooks(&self) -> Option<WebhookConfig> {
+        (!self.webhooks.urls.is_empty()).then(|| WebhookConfig {
+            urls: self.webhooks.urls.clone(),
+            secret: self.webhooks.secret.clone(),
+            retry: RetryPolicy {
+                retries: self.webhooks.retries,
+                base: Duration::from_secs(self.webhooks.backoff_secs),
+                max: Duration::from_secs(self.webhooks.max_backoff_secs),
+            },
+        })
+    }
+
+    pub fn tokens(&self) -> Option<TokenConfig> {
+        let secret = self.auth.token_secret.as_ref()?;
+        let api_key = self.auth.token_api_key.as_ref()?;
+
+        Some(TokenConfig {
+            secret: secret.as_bytes().into(),
+            api_key: api_key.as_str().into(),
+            ttl: Duration::from_secs(self.auth.token_ttl_secs),
+        })
+    }
+}
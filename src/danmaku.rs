@@ -0,0 +1,441 @@
+//! Client for the live broadcast message ("danmaku") protocol, used to catch
+//! guard purchases as they happen instead of waiting for the topList API.
+//!
+//! Every WebSocket frame carries one or more packets, each with a 16 byte
+//! big-endian header:
+//!
+//! | offset | size | field                         |
+//! |--------|------|-------------------------------|
+//! | 0      | 4    | packet length, header included |
+//! | 4      | 2    | header length, always 16      |
+//! | 6      | 2    | protocol version              |
+//! | 8      | 4    | operation                     |
+//! | 12     | 4    | sequence, always 1            |
+//!
+//! Bodies of protocol version 2 and 3 are zlib and brotli compressed
+//! packets in turn.
+
+use std::{io::Read, sync::Arc, time::Duration};
+
+use anyhow::{Context, Result, bail};
+use flate2::read::ZlibDecoder;
+use futures_util::{SinkExt, StreamExt};
+use serde::Deserialize;
+use serde_json::json;
+use tokio::task::JoinHandle;
+use tokio_tungstenite::tungstenite::Message;
+use tracing::{debug, info, warn};
+
+use mulike::{CaptainEntry, GuardLevel, retry::RetryPolicy};
+
+use crate::cache::RosterCache;
+
+pub const HEADER_LEN: usize = 16;
+
+const DEFAULT_HOST: &str = "broadcastlv.chat.bilibili.com";
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Bodies of version 0 are plain JSON messages.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protover {
+    /// Heartbeats and their replies.
+    Int = 1,
+    Zlib = 2,
+    Brotli = 3,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operation {
+    Heartbeat = 2,
+    HeartbeatReply = 3,
+    Message = 5,
+    Auth = 7,
+    AuthReply = 8,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Packet {
+    pub protover: u16,
+    pub op: u32,
+    pub body: Vec<u8>,
+}
+
+impl Packet {
+    pub fn new(protover: Protover, op: Operation, body: impl Into<Vec<u8>>) -> Self {
+        Self {
+            protover: protover as u16,
+            op: op as u32,
+            body: body.into(),
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(HEADER_LEN + self.body.len());
+        buf.extend_from_slice(&((HEADER_LEN + self.body.len()) as u32).to_be_bytes());
+        buf.extend_from_slice(&(HEADER_LEN as u16).to_be_bytes());
+        buf.extend_from_slice(&self.protover.to_be_bytes());
+        buf.extend_from_slice(&self.op.to_be_bytes());
+        buf.extend_from_slice(&1u32.to_be_bytes());
+        buf.extend_from_slice(&self.body);
+        buf
+    }
+
+    /// Splits a frame into its packets, unpacking compressed ones.
+    pub fn decode_all(mut buf: &[u8]) -> Result<Vec<Self>> {
+        let mut packets = vec![];
+
+        while !buf.is_empty() {
+            if buf.len() < HEADER_LEN {
+                bail!("truncated packet header: {} bytes", buf.len());
+            }
+
+            let len = u32::from_be_bytes(buf[0..4].try_into()?) as usize;
+            let header_len = u16::from_be_bytes(buf[4..6].try_into()?) as usize;
+            let protover = u16::from_be_bytes(buf[6..8].try_into()?);
+            let op = u32::from_be_bytes(buf[8..12].try_into()?);
+
+            if header_len < HEADER_LEN || len < header_len || len > buf.len() {
+                bail!(
+                    "malformed packet: length {len}, header length {header_len}, {} bytes left",
+                    buf.len()
+                );
+            }
+
+            let body = &buf[header_len..len];
+
+            match protover {
+                v if v == Protover::Zlib as u16 => {
+                    let mut inflated = vec![];
+                    ZlibDecoder::new(body).read_to_end(&mut inflated)?;
+                    packets.extend(Self::decode_all(&inflated)?);
+                }
+                v if v == Protover::Brotli as u16 => {
+                    let mut inflated = vec![];
+                    brotli::Decompressor::new(body, 4096).read_to_end(&mut inflated)?;
+                    packets.extend(Self::decode_all(&inflated)?);
+                }
+                _ => packets.push(Self {
+                    protover,
+                    op,
+                    body: body.to_vec(),
+                }),
+            }
+
+            buf = &buf[len..];
+        }
+
+        Ok(packets)
+    }
+}
+
+/// The `cmd`s we care about. `cmd` sometimes carries a `:`-separated suffix.
+#[derive(Debug, Deserialize)]
+struct Command {
+    cmd: String,
+    data: Option<serde_json::Value>,
+}
+
+/// The common part of `GUARD_BUY` and `USER_TOAST_MSG`.
+#[derive(Debug, Deserialize)]
+struct GuardPurchase {
+    uid: u64,
+    username: String,
+    guard_level: GuardLevel,
+}
+
+impl From<GuardPurchase> for CaptainEntry {
+    fn from(p: GuardPurchase) -> Self {
+        Self {
+            uid: p.uid,
+            username: p.username,
+            rank: 0,
+            guard_level: p.guard_level,
+            accompany: 0,
+            face: String::new(),
+            medal_info: None,
+        }
+    }
+}
+
+/// Picks a guard purchase out of a message packet body, if it is one.
+fn guard_purchase(body: &[u8]) -> Result<Option<CaptainEntry>> {
+    let command = serde_json::from_slice::<Command>(body)?;
+
+    let cmd = command.cmd.split(':').next().unwrap_or_default();
+    if cmd != "GUARD_BUY" && cmd != "USER_TOAST_MSG" {
+        return Ok(None);
+    }
+
+    let data = command.data.context("guard purchase without data")?;
+    Ok(Some(serde_json::from_value::<GuardPurchase>(data)?.into()))
+}
+
+#[derive(Debug, Deserialize)]
+struct DanmuInfo {
+    code: i32,
+    #[serde(default)]
+    message: String,
+    data: Option<DanmuInfoData>,
+}
+
+#[derive(Debug, Deserialize)]
+struct DanmuInfoData {
+    token: String,
+    host_list: Vec<DanmuHost>,
+}
+
+#[derive(Debug, Deserialize)]
+struct DanmuHost {
+    host: String,
+    wss_port: u16,
+}
+
+/// Where to connect and the key to authenticate with. Without a key the
+/// server still talks to us, with less detail.
+async fn get_danmu_server(roomid: u32, client: &reqwest::Client) -> (String, String) {
+    let info = async {
+        let info = client
+            .get("https://api.live.bilibili.com/xlive/web-room/v1/index/getDanmuInfo")
+            .query(&[("id", roomid.to_string()), ("type", "0".to_string())])
+            .send()
+            .await?
+            .error_for_status()?
+            .json::<DanmuInfo>()
+            .await?;
+
+        if info.code != 0 {
+            bail!("{} ({})", info.message, info.code);
+        }
+
+        info.data.context("getDanmuInfo has no data")
+    };
+
+    match info.await {
+        Ok(DanmuInfoData { token, host_list }) => match host_list.first() {
+            Some(host) => (format!("wss://{}:{}/sub", host.host, host.wss_port), token),
+            None => (format!("wss://{DEFAULT_HOST}/sub"), token),
+        },
+        Err(e) => {
+            warn!("Failed to get the danmaku server of room {roomid}, connecting anonymously: {e}");
+            (format!("wss://{DEFAULT_HOST}/sub"), String::new())
+        }
+    }
+}
+
+/// Connects to the message server at `url`, joins `roomid` and calls
+/// `on_purchase` for every guard purchase until the connection drops.
+pub async fn listen(
+    url: &str,
+    roomid: u32,
+    key: &str,
+    mut on_purchase: impl FnMut(CaptainEntry),
+) -> Result<()> {
+    let (socket, _) = tokio_tungstenite::connect_async(url).await?;
+    let (mut write, mut read) = socket.split();
+
+    let auth = json!({
+        "uid": 0,
+        "roomid": roomid,
+        "protover": Protover::Brotli as u16,
+        "platform": "web",
+        "type": 2,
+        "key": key,
+    });
+    let auth = Packet::new(Protover::Int, Operation::Auth, serde_json::to_vec(&auth)?);
+    write.send(Message::Binary(auth.encode().into())).await?;
+
+    let heartbeat = Packet::new(Protover::Int, Operation::Heartbeat, "[object Object]").encode();
+    let mut ticker = tokio::time::interval(HEARTBEAT_INTERVAL);
+
+    loop {
+        tokio::select! {
+            _ = ticker.tick() => {
+                write.send(Message::Binary(heartbeat.clone().into())).await?;
+            }
+            message = read.next() => {
+                let frame = match message {
+                    Some(Ok(Message::Binary(frame))) => frame,
+                    Some(Ok(Message::Close(_))) | None => return Ok(()),
+                    Some(Ok(_)) => continue,
+                    Some(Err(e)) => return Err(e.into()),
+                };
+
+                for packet in Packet::decode_all(&frame)? {
+                    match packet.op {
+                        op if op == Operation::HeartbeatReply as u32 => {}
+                        op if op == Operation::AuthReply as u32 => {
+                            debug!("Joined the message stream of room {roomid}");
+                        }
+                        op if op == Operation::Message as u32 => {
+                            match guard_purchase(&packet.body) {
+                                Ok(Some(entry)) => on_purchase(entry),
+                                Ok(None) => {}
+                                Err(e) => warn!("Unreadable message in room {roomid}: {e}"),
+                            }
+                        }
+                        _ => {}
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Keeps listening to the messages of the room of `cache`, reconnecting
+/// with `retry`'s backoff, and merges guard purchases into the roster.
+pub fn spawn(
+    cache: Arc<RosterCache>,
+    client: reqwest::Client,
+    retry: RetryPolicy,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let roomid = cache.roomid();
+        let mut failures = 0;
+
+        loop {
+            let (url, key) = get_danmu_server(roomid, &client).await;
+
+            let result = listen(&url, roomid, &key, |entry| {
+                info!(
+                    "{} ({}) bought {:?} in room {roomid}",
+                    entry.username, entry.uid, entry.guard_level
+                );
+                failures = 0;
+                cache.merge_purchase(entry);
+            })
+            .await;
+
+            match result {
+                Ok(()) => warn!("Message stream of room {roomid} closed, reconnecting"),
+                Err(e) => warn!("Message stream of room {roomid} failed: {e}"),
+            }
+
+            tokio::time::sleep(retry.backoff(failures)).await;
+            failures = failures.saturating_add(1);
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use flate2::{Compression, write::ZlibEncoder};
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    fn guard_buy() -> Packet {
+        let body = json!({
+            "cmd": "GUARD_BUY",
+            "data": {"uid": 42, "username": "mulyn", "guard_level": 3},
+        });
+        Packet::new(Protover::Int, Operation::Message, body.to_string())
+    }
+
+    fn zlib_packet(packets: &[Packet]) -> Packet {
+        let mut encoder = ZlibEncoder::new(vec![], Compression::default());
+        for packet in packets {
+            encoder.write_all(&packet.encode()).unwrap();
+        }
+        Packet::new(
+            Protover::Zlib,
+            Operation::Message,
+            encoder.finish().unwrap(),
+        )
+    }
+
+    fn brotli_packet(packets: &[Packet]) -> Packet {
+        let mut compressed = vec![];
+        {
+            let mut writer = brotli::CompressorWriter::new(&mut compressed, 4096, 5, 22);
+            for packet in packets {
+                writer.write_all(&packet.encode()).unwrap();
+            }
+        }
+        Packet::new(Protover::Brotli, Operation::Message, compressed)
+    }
+
+    #[test]
+    fn decodes_plain_packets() {
+        let heartbeat = Packet::new(Protover::Int, Operation::HeartbeatReply, [0, 0, 0, 7]);
+        let mut frame = heartbeat.encode();
+        frame.extend(guard_buy().encode());
+
+        assert_eq!(
+            Packet::decode_all(&frame).unwrap(),
+            vec![heartbeat, guard_buy()]
+        );
+    }
+
+    #[test]
+    fn decodes_compressed_packets() {
+        let packets = vec![guard_buy(), guard_buy()];
+
+        assert_eq!(
+            Packet::decode_all(&zlib_packet(&packets).encode()).unwrap(),
+            packets
+        );
+        assert_eq!(
+            Packet::decode_all(&brotli_packet(&packets).encode()).unwrap(),
+            packets
+        );
+    }
+
+    #[test]
+    fn rejects_truncated_packets() {
+        let frame = guard_buy().encode();
+
+        assert!(Packet::decode_all(&frame[..HEADER_LEN - 1]).is_err());
+        assert!(Packet::decode_all(&frame[..frame.len() - 1]).is_err());
+    }
+
+    #[tokio::test]
+    async fn listen_reports_guard_purchases() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}/sub", listener.local_addr().unwrap());
+
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut socket = tokio_tungstenite::accept_async(stream).await.unwrap();
+
+            let Some(Ok(Message::Binary(auth))) = socket.next().await else {
+                panic!("expected the auth packet");
+            };
+            let auth = Packet::decode_all(&auth).unwrap().remove(0);
+            assert_eq!(auth.op, Operation::Auth as u32);
+            let auth = serde_json::from_slice::<serde_json::Value>(&auth.body).unwrap();
+            assert_eq!(auth["roomid"], 1234);
+            assert_eq!(auth["key"], "key");
+
+            // the first heartbeat goes out right away
+            let Some(Ok(Message::Binary(_))) = socket.next().await else {
+                panic!("expected a heartbeat");
+            };
+
+            let reply = Packet::new(Protover::Int, Operation::AuthReply, r#"{"code":0}"#);
+            socket
+                .send(Message::Binary(reply.encode().into()))
+                .await
+                .unwrap();
+            socket
+                .send(Message::Binary(
+                    brotli_packet(&[guard_buy()]).encode().into(),
+                ))
+                .await
+                .unwrap();
+            socket.close(None).await.unwrap();
+        });
+
+        let mut purchases = vec![];
+        listen(&url, 1234, "key", |entry| purchases.push(entry))
+            .await
+            .unwrap();
+        server.await.unwrap();
+
+        assert_eq!(purchases.len(), 1);
+        assert_eq!(purchases[0].uid, 42);
+        assert_eq!(purchases[0].username, "mulyn");
+        assert_eq!(purchases[0].guard_level, GuardLevel::Captain);
+    }
+}
@@ -0,0 +1,193 @@
+use std::collections::HashMap;
+
+use serde::Serialize;
+
+use crate::{CaptainEntry, GuardLevel};
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TierChange {
+    pub uid: u64,
+    pub username: String,
+    pub from: GuardLevel,
+    pub to: GuardLevel,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Rename {
+    pub uid: u64,
+    pub from: String,
+    pub to: String,
+}
+
+/// What changed between two rosters, matching people by UID.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct RosterDiff {
+    pub joined: Vec<CaptainEntry>,
+    pub left: Vec<CaptainEntry>,
+    pub upgraded: Vec<TierChange>,
+    pub downgraded: Vec<TierChange>,
+    pub renamed: Vec<Rename>,
+}
+
+impl RosterDiff {
+    pub fn is_empty(&self) -> bool {
+        self.joined.is_empty()
+            && self.left.is_empty()
+            && self.upgraded.is_empty()
+            && self.downgraded.is_empty()
+            && self.renamed.is_empty()
+    }
+}
+
+/// Compares an `old` roster with a `new` one. Results follow the order of
+/// `new`, except for `left` which follows `old`.
+pub fn diff_rosters(old: &[CaptainEntry], new: &[CaptainEntry]) -> RosterDiff {
+    let old_by_uid = old.iter().map(|e| (e.uid, e)).collect::<HashMap<_, _>>();
+    let new_by_uid = new.iter().map(|e| (e.uid, e)).collect::<HashMap<_, _>>();
+
+    let mut diff = RosterDiff {
+        left: old
+            .iter()
+            .filter(|e| !new_by_uid.contains_key(&e.uid))
+            .cloned()
+            .collect(),
+        ..Default::default()
+    };
+
+    for entry in new {
+        let Some(before) = old_by_uid.get(&entry.uid) else {
+            diff.joined.push(entry.clone());
+            continue;
+        };
+
+        if before.username != entry.username {
+            diff.renamed.push(Rename {
+                uid: entry.uid,
+                from: before.username.clone(),
+                to: entry.username.clone(),
+            });
+        }
+
+        let change = TierChange {
+            uid: entry.uid,
+            username: entry.username.clone(),
+            from: before.guard_level,
+            to: entry.guard_level,
+        };
+
+        // a lower level number is a higher tier
+        match u8::from(entry.guard_level).cmp(&u8::from(before.guard_level)) {
+            std::cmp::Ordering::Less => diff.upgraded.push(change),
+            std::cmp::Ordering::Greater => diff.downgraded.push(change),
+            std::cmp::Ordering::Equal => {}
+        }
+    }
+
+    diff
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(uid: u64, username: &str, guard_level: GuardLevel) -> CaptainEntry {
+        CaptainEntry {
+            uid,
+            username: username.to_string(),
+            rank: 0,
+            guard_level,
+            accompany: 0,
+            face: String::new(),
+            medal_info: None,
+        }
+    }
+
+    fn uids(entries: &[CaptainEntry]) -> Vec<u64> {
+        entries.iter().map(|e| e.uid).collect()
+    }
+
+    #[test]
+    fn same_rosters_have_no_changes() {
+        let roster = [
+            entry(1, "a", GuardLevel::Governor),
+            entry(2, "b", GuardLevel::Captain),
+        ];
+
+        assert!(diff_rosters(&roster, &roster).is_empty());
+    }
+
+    #[test]
+    fn finds_joined_and_left() {
+        let old = [
+            entry(1, "a", GuardLevel::Captain),
+            entry(2, "b", GuardLevel::Captain),
+            entry(3, "c", GuardLevel::Captain),
+        ];
+        let new = [
+            entry(4, "d", GuardLevel::Captain),
+            entry(2, "b", GuardLevel::Captain),
+            entry(5, "e", GuardLevel::Captain),
+        ];
+
+        let diff = diff_rosters(&old, &new);
+
+        assert_eq!(uids(&diff.joined), [4, 5]);
+        assert_eq!(uids(&diff.left), [1, 3]);
+        assert!(diff.upgraded.is_empty());
+        assert!(diff.downgraded.is_empty());
+        assert!(diff.renamed.is_empty());
+    }
+
+    #[test]
+    fn finds_tier_changes() {
+        let old = [
+            entry(1, "a", GuardLevel::Captain),
+            entry(2, "b", GuardLevel::Governor),
+            entry(3, "c", GuardLevel::Admiral),
+        ];
+        let new = [
+            entry(1, "a", GuardLevel::Admiral),
+            entry(2, "b", GuardLevel::Captain),
+            entry(3, "c", GuardLevel::Admiral),
+        ];
+
+        let diff = diff_rosters(&old, &new);
+
+        let [upgraded] = diff.upgraded.as_slice() else {
+            panic!("expected one upgrade, got {:?}", diff.upgraded);
+        };
+        assert_eq!(upgraded.uid, 1);
+        assert_eq!(upgraded.from, GuardLevel::Captain);
+        assert_eq!(upgraded.to, GuardLevel::Admiral);
+
+        let [downgraded] = diff.downgraded.as_slice() else {
+            panic!("expected one downgrade, got {:?}", diff.downgraded);
+        };
+        assert_eq!(downgraded.uid, 2);
+        assert_eq!(downgraded.from, GuardLevel::Governor);
+        assert_eq!(downgraded.to, GuardLevel::Captain);
+
+        assert!(diff.joined.is_empty());
+        assert!(diff.left.is_empty());
+    }
+
+    #[test]
+    fn finds_renames_by_uid() {
+        let old = [entry(1, "before", GuardLevel::Captain)];
+        let new = [entry(1, "after", GuardLevel::Admiral)];
+
+        let diff = diff_rosters(&old, &new);
+
+        let [rename] = diff.renamed.as_slice() else {
+            panic!("expected one rename, got {:?}", diff.renamed);
+        };
+        assert_eq!(rename.uid, 1);
+        assert_eq!(rename.from, "before");
+        assert_eq!(rename.to, "after");
+
+        // the tier change carries the new name
+        assert_eq!(diff.upgraded[0].username, "after");
+        assert!(diff.joined.is_empty());
+        assert!(diff.left.is_empty());
+    }
+}
@@ -0,0 +1,323 @@
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use axum::response::sse::Event;
+use futures_util::{Stream, stream};
+use serde::Serialize;
+use tokio::sync::{
+    broadcast::{self, error::RecvError},
+    watch,
+};
+use tracing::warn;
+
+use mulike::{CaptainEntry, GuardLevel};
+
+use crate::{
+    cache::{RosterCache, RosterUpdate},
+    room::Room,
+    server::ShareState,
+};
+
+/// How many events are kept for clients resuming with `Last-Event-ID`.
+const RECENT_EVENTS: usize = 256;
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum RosterEvent {
+    Join {
+        entry: CaptainEntry,
+    },
+    Leave {
+        entry: CaptainEntry,
+    },
+    Rename {
+        uid: u64,
+        from: String,
+        to: String,
+    },
+    TierChange {
+        uid: u64,
+        username: String,
+        from: GuardLevel,
+        to: GuardLevel,
+    },
+    /// Some changes were missed; the full roster has to be fetched again.
+    Resync,
+}
+
+impl RosterEvent {
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Join { .. } => "join",
+            Self::Leave { .. } => "leave",
+            Self::Rename { .. } => "rename",
+            Self::TierChange { .. } => "tier_change",
+            Self::Resync => "resync",
+        }
+    }
+
+    fn from_update(update: &RosterUpdate) -> impl Iterator<Item = Self> + '_ {
+        let diff = &update.diff;
+
+        let joined = diff.joined.iter().map(|entry| Self::Join {
+            entry: entry.clone(),
+        });
+        let left = diff.left.iter().map(|entry| Self::Leave {
+            entry: entry.clone(),
+        });
+        let renamed = diff.renamed.iter().map(|r| Self::Rename {
+            uid: r.uid,
+            from: r.from.clone(),
+            to: r.to.clone(),
+        });
+        let tiers = diff
+            .upgraded
+            .iter()
+            .chain(&diff.downgraded)
+            .map(|c| Self::TierChange {
+                uid: c.uid,
+                username: c.username.clone(),
+                from: c.from,
+                to: c.to,
+            });
+
+        joined.chain(left).chain(renamed).chain(tiers)
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct LoggedEvent {
+    pub id: u64,
+    pub roomid: u32,
+    #[serde(flatten)]
+    pub event: RosterEvent,
+}
+
+#[derive(Debug)]
+struct Recent {
+    next_id: u64,
+    events: VecDeque<Arc<LoggedEvent>>,
+}
+
+/// Numbered roster events of one room, with the most recent ones kept for
+/// replay.
+///
+/// IDs start from the startup time in milliseconds, so they keep growing
+/// across restarts and IDs from a previous run are never mistaken for
+/// current ones.
+#[derive(Debug)]
+pub struct EventLog {
+    roomid: u32,
+    recent: Mutex<Recent>,
+    tx: broadcast::Sender<Arc<LoggedEvent>>,
+}
+
+impl EventLog {
+    /// Starts turning the roster updates of `cache` into events.
+    pub fn spawn(cache: &RosterCache) -> Arc<Self> {
+        let first_id = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+
+        let log = Arc::new(Self {
+            roomid: cache.roomid(),
+            recent: Mutex::new(Recent {
+                next_id: first_id,
+                events: VecDeque::with_capacity(RECENT_EVENTS),
+            }),
+            tx: broadcast::channel(RECENT_EVENTS).0,
+        });
+
+        let mut updates = cache.subscribe();
+        let pump = log.clone();
+
+        tokio::spawn(async move {
+            loop {
+                match updates.recv().await {
+                    Ok(update) => RosterEvent::from_update(&update).for_each(|e| pump.push(e)),
+                    Err(RecvError::Lagged(n)) => {
+                        warn!(
+                            "Event log of room {} skipped {n} roster changes",
+                            pump.roomid
+                        );
+                        pump.push(RosterEvent::Resync);
+                    }
+                    Err(RecvError::Closed) => return,
+                }
+            }
+        });
+
+        log
+    }
+
+    fn push(&self, event: RosterEvent) {
+        let mut recent = self.recent.lock().unwrap();
+
+        let event = Arc::new(LoggedEvent {
+            id: recent.next_id,
+            roomid: self.roomid,
+            event,
+        });
+        recent.next_id += 1;
+
+        if recent.events.len() == RECENT_EVENTS {
+            recent.events.pop_front();
+        }
+        recent.events.push_back(event.clone());
+
+        // sent under the lock, so `subscribe` never sees an event twice or not at all
+        let _ = self.tx.send(event);
+    }
+
+    /// The ID of the newest event, which is also what a full roster sent now is as new as.
+    pub fn last_id(&self) -> u64 {
+        self.recent.lock().unwrap().next_id - 1
+    }
+
+    /// Subscribes to new events. With `after`, also returns the events since
+    /// then, or `None` if they are no longer all kept.
+    pub fn subscribe(
+        &self,
+        after: Option<u64>,
+    ) -> (
+        Option<Vec<Arc<LoggedEvent>>>,
+        broadcast::Receiver<Arc<LoggedEvent>>,
+    ) {
+        let recent = self.recent.lock().unwrap();
+
+        let missed = after.and_then(|after| {
+            let oldest = recent.events.front().map_or(recent.next_id, |e| e.id);
+
+            (after + 1 >= oldest && after < recent.next_id).then(|| {
+                recent
+                    .events
+                    .iter()
+                    .filter(|e| e.id > after)
+                    .cloned()
+                    .collect()
+            })
+        });
+
+        (missed, self.tx.subscribe())
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct SnapshotEvent<'a> {
+    roomid: u32,
+    entries: &'a [CaptainEntry],
+}
+
+async fn snapshot(room: &Room) -> Result<Event, axum::Error> {
+    let id = room.events.last_id();
+    let snapshot = room.cache.get().await.map_err(axum::Error::new)?;
+
+    Event::default()
+        .id(id.to_string())
+        .event("snapshot")
+        .json_data(SnapshotEvent {
+            roomid: room.roomid(),
+            entries: &snapshot.entries,
+        })
+}
+
+fn event(e: &LoggedEvent) -> Result<Event, axum::Error> {
+    Event::default()
+        .id(e.id.to_string())
+        .event(e.event.name())
+        .json_data(e)
+}
+
+/// Tells the client its room was removed, as the last event of the stream.
+fn removed(room: &Room) -> Event {
+    Event::default()
+        .event("removed")
+        .data(format!("Room {} is no longer served", room.roomid()))
+}
+
+struct SseState {
+    room: Arc<Room>,
+    pending: VecDeque<Arc<LoggedEvent>>,
+    rx: broadcast::Receiver<Arc<LoggedEvent>>,
+    needs_snapshot: bool,
+    reloads: watch::Receiver<ShareState>,
+    removed: bool,
+}
+
+impl SseState {
+    fn served(&self) -> bool {
+        self.reloads
+            .borrow()
+            .rooms
+            .iter()
+            .any(|room| Arc::ptr_eq(room, &self.room))
+    }
+}
+
+/// Server-sent events for `room`: the full roster on connect, or the missed
+/// events when resuming from `last_id`, then every change as it happens.
+///
+/// Ends with a `removed` event once a reload of `reloads` stops the room.
+pub fn sse(
+    room: Arc<Room>,
+    last_id: Option<u64>,
+    reloads: watch::Receiver<ShareState>,
+) -> impl Stream<Item = Result<Event, axum::Error>> {
+    let (missed, rx) = room.events.subscribe(last_id);
+
+    let state = SseState {
+        room,
+        needs_snapshot: missed.is_none(),
+        pending: missed.unwrap_or_default().into(),
+        rx,
+        reloads,
+        removed: false,
+    };
+
+    stream::unfold(state, |mut state| async move {
+        if state.removed {
+            return None;
+        }
+
+        loop {
+            if state.needs_snapshot {
+                state.needs_snapshot = false;
+                let e = snapshot(&state.room).await;
+                return Some((e, state));
+            }
+
+            let next = match state.pending.pop_front() {
+                Some(e) => e,
+                None => tokio::select! {
+                    received = state.rx.recv() => match received {
+                        Ok(e) => e,
+                        Err(RecvError::Lagged(_)) => {
+                            state.needs_snapshot = true;
+                            continue;
+                        }
+                        Err(RecvError::Closed) => return None,
+                    },
+                    Ok(()) = state.reloads.changed() => {
+                        if state.served() {
+                            continue;
+                        }
+
+                        state.removed = true;
+                        return Some((Ok(removed(&state.room)), state));
+                    }
+                },
+            };
+
+            if matches!(next.event, RosterEvent::Resync) {
+                state.needs_snapshot = true;
+                continue;
+            }
+
+            return Some((event(&next), state));
+        }
+    })
+}
@@ -0,0 +1,150 @@
+use mulike::CaptainEntry;
+
+const HEADER: [&str; 7] = [
+    "uid",
+    "username",
+    "guard_level",
+    "rank",
+    "accompany",
+    "medal_name",
+    "medal_level",
+];
+
+/// Excel only detects UTF-8 in a CSV file when it starts with a byte order mark.
+const BOM: &str = "\u{feff}";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Delimited {
+    Csv,
+    Tsv,
+}
+
+impl Delimited {
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Self::Csv => "text/csv; charset=utf-8",
+            Self::Tsv => "text/tab-separated-values; charset=utf-8",
+        }
+    }
+
+    fn separator(self) -> char {
+        match self {
+            Self::Csv => ',',
+            Self::Tsv => '\t',
+        }
+    }
+
+    /// CSV quotes fields as in RFC 4180. TSV has no quoting, so tabs and line
+    /// breaks inside a field are replaced with spaces.
+    fn field(self, value: &str, out: &mut String) {
+        match self {
+            Self::Csv => {
+                if value.contains([',', '"', '\n', '\r']) {
+                    out.push('"');
+                    out.push_str(&value.replace('"', "\"\""));
+                    out.push('"');
+                } else {
+                    out.push_str(value);
+                }
+            }
+            Self::Tsv => out.extend(value.chars().map(|c| match c {
+                '\t' | '\n' | '\r' => ' ',
+                c => c,
+            })),
+        }
+    }
+
+    fn row<'a>(self, fields: impl IntoIterator<Item = &'a str>, out: &mut String) {
+        for (i, field) in fields.into_iter().enumerate() {
+            if i > 0 {
+                out.push(self.separator());
+            }
+            self.field(field, out);
+        }
+        out.push_str("\r\n");
+    }
+
+    pub fn render(self, list: &[CaptainEntry], bom: bool) -> String {
+        let mut out = String::new();
+
+        if bom {
+            out.push_str(BOM);
+        }
+
+        self.row(HEADER, &mut out);
+
+        for entry in list {
+            let (medal_name, medal_level) = match &entry.medal_info {
+                Some(medal) => (medal.medal_name.as_str(), medal.medal_level.to_string()),
+                None => ("", String::new()),
+            };
+
+            self.row(
+                [
+                    entry.uid.to_string().as_str(),
+                    &entry.username,
+                    u8::from(entry.guard_level).to_string().as_str(),
+                    entry.rank.to_string().as_str(),
+                    entry.accompany.to_string().as_str(),
+                    medal_name,
+                    &medal_level,
+                ],
+                &mut out,
+            );
+        }
+
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mulike::{GuardLevel, MedalInfo};
+
+    use super::*;
+
+    fn field(delimited: Delimited, value: &str) -> String {
+        let mut out = String::new();
+        delimited.field(value, &mut out);
+        out
+    }
+
+    #[test]
+    fn csv_quotes_only_when_needed() {
+        assert_eq!(field(Delimited::Csv, "plain 名字"), "plain 名字");
+        assert_eq!(field(Delimited::Csv, "a,b"), r#""a,b""#);
+        assert_eq!(field(Delimited::Csv, r#"say "hi""#), r#""say ""hi""""#);
+        assert_eq!(field(Delimited::Csv, "two\nlines"), "\"two\nlines\"");
+        assert_eq!(field(Delimited::Csv, "cr\r"), "\"cr\r\"");
+        assert_eq!(field(Delimited::Csv, ""), "");
+    }
+
+    #[test]
+    fn tsv_replaces_separators() {
+        assert_eq!(field(Delimited::Tsv, "a\tb\nc\rd"), "a b c d");
+        assert_eq!(field(Delimited::Tsv, r#"a,"b""#), r#"a,"b""#);
+    }
+
+    #[test]
+    fn renders_rows() {
+        let list = [CaptainEntry {
+            uid: 1,
+            username: "a,\"b\"".to_string(),
+            rank: 2,
+            guard_level: GuardLevel::Captain,
+            accompany: 30,
+            face: String::new(),
+            medal_info: Some(MedalInfo {
+                medal_name: "粉丝".to_string(),
+                medal_level: 21,
+            }),
+        }];
+
+        assert_eq!(
+            Delimited::Csv.render(&list, false),
+            "uid,username,guard_level,rank,accompany,medal_name,medal_level\r\n\
+             1,\"a,\"\"b\"\"\",3,2,30,粉丝,21\r\n"
+        );
+        assert!(Delimited::Csv.render(&[], true).starts_with(BOM));
+    }
+}
@@ -0,0 +1,338 @@
+use pinyin::ToPinyin;
+use regex::{Regex, RegexBuilder};
+use serde::Deserialize;
+use zhconv::{Variant, zhconv};
+
+use mulike::CaptainEntry;
+
+/// Upper bound on the compiled size of a `match=regex` pattern, so a
+/// request can't make us build a huge automaton.
+const REGEX_SIZE_LIMIT: usize = 1 << 16;
+
+/// How `?username=` is compared with usernames.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MatchMode {
+    Exact,
+    Prefix,
+    #[default]
+    Contains,
+    /// Exact, ignoring case and width, and traditional vs simplified Chinese.
+    Icase,
+    Regex,
+    /// Approximate, also by pinyin and pinyin initials; best matches first.
+    Fuzzy,
+}
+
+/// Matches usernames against one or more wanted names.
+#[derive(Debug, Clone)]
+pub enum UsernameFilter {
+    Exact(Vec<String>),
+    Prefix(Vec<String>),
+    Contains(Vec<String>),
+    /// [`normalize`]d.
+    Icase(Vec<String>),
+    Regex(Regex),
+    /// [`normalize`]d.
+    Fuzzy(Vec<String>),
+}
+
+/// Folds full-width ASCII to half-width, traditional Chinese to simplified
+/// and everything to lowercase.
+pub fn normalize(s: &str) -> String {
+    let half_width = s
+        .chars()
+        .map(|c| match c {
+            '\u{3000}' => ' ',
+            '\u{ff01}'..='\u{ff5e}' => char::from_u32(c as u32 - 0xfee0).unwrap_or(c),
+            c => c,
+        })
+        .collect::<String>();
+
+    zhconv(&half_width, Variant::ZhHans).to_lowercase()
+}
+
+/// The full pinyin and the pinyin initials (首字母) of `name`, keeping
+/// characters that have no pinyin as they are.
+fn pinyin(name: &str) -> (String, String) {
+    let mut full = String::new();
+    let mut initials = String::new();
+
+    for c in name.chars() {
+        match c.to_pinyin() {
+            Some(p) => {
+                full.push_str(p.plain());
+                initials.push_str(p.first_letter());
+            }
+            None => {
+                full.push(c);
+                initials.push(c);
+            }
+        }
+    }
+
+    (full, initials)
+}
+
+fn levenshtein(a: &[char], b: &[char]) -> usize {
+    let mut row = (0..=b.len()).collect::<Vec<_>>();
+
+    for (i, ca) in a.iter().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = diagonal + usize::from(ca != cb);
+            diagonal = row[j + 1];
+            row[j + 1] = substitution.min(row[j] + 1).min(row[j + 1] + 1);
+        }
+    }
+
+    row[b.len()]
+}
+
+/// Number of characters skipped in `haystack` to find `needle` as a
+/// subsequence, or `None` if it isn't one.
+fn subsequence_gaps(needle: &[char], haystack: &[char]) -> Option<usize> {
+    let mut rest = haystack.iter();
+    let mut gaps = 0;
+
+    for c in needle {
+        gaps += rest.position(|h| h == c)?;
+    }
+
+    Some(gaps)
+}
+
+/// Scores how well `query` matches `candidate`, higher is better.
+fn fuzzy_score(query: &str, candidate: &str) -> Option<u32> {
+    if candidate == query {
+        return Some(1000);
+    }
+    if candidate.starts_with(query) {
+        return Some(800);
+    }
+    if candidate.contains(query) {
+        return Some(600);
+    }
+
+    let query = query.chars().collect::<Vec<_>>();
+    let candidate = candidate.chars().collect::<Vec<_>>();
+
+    if let Some(gaps) = subsequence_gaps(&query, &candidate) {
+        return Some(500u32.saturating_sub(gaps as u32 * 10).max(300));
+    }
+
+    // allow about one typo for every three characters
+    let distance = levenshtein(&query, &candidate);
+    (distance <= (query.len() / 3).max(1)).then(|| 250u32.saturating_sub(distance as u32 * 50))
+}
+
+impl UsernameFilter {
+    /// `usernames` is a comma-separated list, except for [`MatchMode::Regex`]
+    /// where it is a single pattern, since commas are common in patterns.
+    pub fn new(mode: MatchMode, usernames: &str) -> Result<Self, regex::Error> {
+        let names = || {
+            usernames
+                .split(',')
+                .map(str::trim)
+                .filter(|name| !name.is_empty())
+                .map(str::to_string)
+                .collect::<Vec<_>>()
+        };
+
+        Ok(match mode {
+            MatchMode::Exact => Self::Exact(names()),
+            MatchMode::Prefix => Self::Prefix(names()),
+            MatchMode::Contains => Self::Contains(names()),
+            MatchMode::Icase => Self::Icase(names().iter().map(|n| normalize(n)).collect()),
+            MatchMode::Regex => Self::Regex(
+                RegexBuilder::new(usernames)
+                    .size_limit(REGEX_SIZE_LIMIT)
+                    .build()?,
+            ),
+            MatchMode::Fuzzy => Self::Fuzzy(names().iter().map(|n| normalize(n)).collect()),
+        })
+    }
+
+    pub fn matches(&self, username: &str) -> bool {
+        self.score(username).is_some()
+    }
+
+    /// How well `username` matches, higher is better. Every mode but
+    /// [`MatchMode::Fuzzy`] scores all matches the same.
+    pub fn score(&self, username: &str) -> Option<u32> {
+        let matched = match self {
+            Self::Exact(names) => names.iter().any(|n| n == username),
+            Self::Prefix(names) => names.iter().any(|n| username.starts_with(n.as_str())),
+            Self::Contains(names) => names.iter().any(|n| username.contains(n.as_str())),
+            Self::Icase(names) => names.contains(&normalize(username)),
+            Self::Regex(regex) => regex.is_match(username),
+            Self::Fuzzy(names) => {
+                let username = normalize(username);
+                let (full, initials) = pinyin(&username);
+
+                return names
+                    .iter()
+                    .flat_map(|n| [&username, &full, &initials].map(|c| fuzzy_score(n, c)))
+                    .flatten()
+                    .max();
+            }
+        };
+
+        matched.then_some(1)
+    }
+
+    /// Keeps the entries that match, best matches first.
+    pub fn apply(&self, list: &mut Vec<CaptainEntry>) {
+        let mut scored = std::mem::take(list)
+            .into_iter()
+            .filter_map(|e| Some((self.score(&e.username)?, e)))
+            .collect::<Vec<_>>();
+
+        // stable, so equally good matches keep their rank order
+        scored.sort_by(|(a, _), (b, _)| b.cmp(a));
+
+        list.extend(scored.into_iter().map(|(_, e)| e));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mulike::GuardLevel;
+
+    use super::*;
+
+    fn filter(mode: MatchMode, usernames: &str) -> UsernameFilter {
+        UsernameFilter::new(mode, usernames).unwrap()
+    }
+
+    fn entry(username: &str) -> CaptainEntry {
+        CaptainEntry {
+            uid: 0,
+            username: username.to_string(),
+            rank: 0,
+            guard_level: GuardLevel::Captain,
+            accompany: 0,
+            face: String::new(),
+            medal_info: None,
+        }
+    }
+
+    fn chars(s: &str) -> Vec<char> {
+        s.chars().collect()
+    }
+
+    fn applied(filter: &UsernameFilter, usernames: &[&str]) -> Vec<String> {
+        let mut list = usernames.iter().map(|name| entry(name)).collect();
+        filter.apply(&mut list);
+        list.into_iter().map(|e| e.username).collect()
+    }
+
+    #[test]
+    fn exact_matches_whole_names() {
+        let exact = filter(MatchMode::Exact, "abc");
+
+        assert!(exact.matches("abc"));
+        assert!(!exact.matches("abcd"));
+        assert!(!exact.matches("ABC"));
+    }
+
+    #[test]
+    fn lists_match_any_name() {
+        let exact = filter(MatchMode::Exact, "abc, 张三,,");
+        assert!(exact.matches("abc"));
+        assert!(exact.matches("张三"));
+        assert!(!exact.matches(""));
+
+        let prefix = filter(MatchMode::Prefix, "ab,cd");
+        assert!(prefix.matches("abx"));
+        assert!(prefix.matches("cdx"));
+        assert!(!prefix.matches("xab"));
+
+        let contains = filter(MatchMode::Contains, "ab,cd");
+        assert!(contains.matches("xabx"));
+        assert!(contains.matches("xcd"));
+        assert!(!contains.matches("ac"));
+    }
+
+    #[test]
+    fn normalizes_width_script_and_case() {
+        assert_eq!(normalize("ＡＢＣ　ｄｅ１"), "abc de1");
+        assert_eq!(normalize("張三"), "张三");
+        assert_eq!(normalize("MiXeD 愛"), "mixed 爱");
+    }
+
+    #[test]
+    fn icase_folds_both_sides() {
+        let icase = filter(MatchMode::Icase, "ＡＢＣ,張三");
+
+        assert!(icase.matches("abc"));
+        assert!(icase.matches("AbC"));
+        assert!(icase.matches("ａｂｃ"));
+        assert!(icase.matches("张三"));
+        assert!(icase.matches("張三"));
+        assert!(!icase.matches("abcd"));
+    }
+
+    #[test]
+    fn limits_regex_size() {
+        assert!(filter(MatchMode::Regex, "^a.c$").matches("abc"));
+        // a regex is a single pattern, commas included
+        assert!(filter(MatchMode::Regex, "a,b").matches("a,b"));
+
+        let huge = UsernameFilter::new(MatchMode::Regex, r"\w{1000}");
+        assert!(matches!(huge, Err(regex::Error::CompiledTooBig(_))));
+    }
+
+    #[test]
+    fn spells_out_pinyin() {
+        assert_eq!(
+            pinyin("张三a1"),
+            ("zhangsana1".to_string(), "zsa1".to_string())
+        );
+    }
+
+    #[test]
+    fn measures_edit_distance() {
+        assert_eq!(levenshtein(&chars(""), &chars("abc")), 3);
+        assert_eq!(levenshtein(&chars("kitten"), &chars("sitting")), 3);
+        assert_eq!(levenshtein(&chars("same"), &chars("same")), 0);
+    }
+
+    #[test]
+    fn counts_subsequence_gaps() {
+        assert_eq!(subsequence_gaps(&chars("ac"), &chars("abc")), Some(1));
+        assert_eq!(subsequence_gaps(&chars("abc"), &chars("abc")), Some(0));
+        assert_eq!(subsequence_gaps(&chars("ca"), &chars("abc")), None);
+    }
+
+    #[test]
+    fn scores_closer_matches_higher() {
+        let scores = ["abc", "abcd", "xabc", "axbxc", "abd", "xyz"].map(|c| fuzzy_score("abc", c));
+
+        assert_eq!(scores[5], None);
+        assert!(scores.windows(2).take(4).all(|w| w[0] > w[1]), "{scores:?}");
+    }
+
+    #[test]
+    fn fuzzy_ranks_by_pinyin() {
+        let fuzzy = filter(MatchMode::Fuzzy, "zhangsan");
+
+        assert_eq!(
+            applied(&fuzzy, &["李四", "张三丰", "張三"]),
+            ["張三", "张三丰"]
+        );
+    }
+
+    #[test]
+    fn fuzzy_ranks_by_initials() {
+        let fuzzy = filter(MatchMode::Fuzzy, "ZS");
+
+        // "ls" is one typo away from "zs"
+        assert_eq!(
+            applied(&fuzzy, &["张三丰", "李四", "张三"]),
+            ["张三", "张三丰", "李四"]
+        );
+    }
+}
@@ -0,0 +1,18 @@
+//! Guard (大航海) rosters of Bilibili live rooms.
+//!
+//! [`BiliLiveClient`] resolves rooms and crawls their guard lists, either
+//! whole with [`BiliLiveClient::guard_list`] or as a stream of pages with
+//! [`BiliLiveClient::guard_pages`]. The `mulike` binary serves them over HTTP.
+
+pub mod client;
+pub mod diff;
+pub mod model;
+pub mod retry;
+pub mod token;
+pub mod upstream;
+
+pub use client::{Anomaly, BiliLiveClient, Crawl, CrawlOptions, CrawlReport, GuardPage, RoomInfo};
+pub use diff::{RosterDiff, diff_rosters};
+pub use model::{CaptainEntry, GuardLevel, MedalInfo};
+pub use token::{MembershipClaims, TokenError, issue_membership_token, verify_membership_token};
+pub use upstream::UpstreamError;
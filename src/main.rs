@@ -1,184 +1,94 @@
-use std::sync::Arc;
-
-use anyhow::Result;
-use axum::{
-    Router,
-    extract::{Query, State},
-    response::{IntoResponse, Response},
-    routing::get,
-};
-use reqwest::{Client, StatusCode};
-use serde::Deserialize;
-use tracing::error;
+mod cache;
+mod cli;
+mod config;
+mod danmaku;
+mod events;
+mod export;
+mod filter;
+mod poller;
+mod reload;
+mod room;
+mod server;
+mod store;
+mod webhook;
+mod ws;
+
+use std::{process::ExitCode, time::Duration};
+
+use clap::Parser;
+use cli::{Cli, Command};
+use config::Config;
+use mulike::{BiliLiveClient, client::USER_AGENT};
 use tracing_subscriber::{EnvFilter, Layer, fmt, layer::SubscriberExt, util::SubscriberInitExt};
 
-#[derive(Debug, Deserialize)]
-struct Captain {
-    // code: i32,
-    data: CaptainData,
-}
-
-#[derive(Debug, Deserialize)]
-struct CaptainData {
-    info: CaptainDataInfo,
-    list: Vec<CaptainEntry>,
-    top3: Option<Vec<CaptainEntry>>,
-}
-
-#[derive(Debug, Deserialize)]
-struct CaptainDataInfo {
-    page: i32,
-}
-
-#[derive(Debug, Deserialize)]
-struct CaptainEntry {
-    username: String,
-}
-
-// learned from https://github.com/tokio-rs/axum/blob/main/examples/anyhow-error-response/src/main.rs
-pub struct AnyhowError(anyhow::Error);
-
-impl IntoResponse for AnyhowError {
-    fn into_response(self) -> Response {
-        error!("Returning internal server error for {}", self.0);
-        (StatusCode::INTERNAL_SERVER_ERROR, format!("{}", self.0)).into_response()
-    }
-}
-
-impl<E> From<E> for AnyhowError
-where
-    E: Into<anyhow::Error>,
-{
-    fn from(err: E) -> Self {
-        Self(err.into())
-    }
-}
-
-#[derive(Debug, Clone)]
-struct ShareState {
-    roomid: u32,
-    ruid: u32,
-    client: Arc<reqwest::Client>,
-}
-
 #[tokio::main]
-async fn main() {
-    dotenvy::dotenv().ok();
-
-    let local_url = std::env::var("LOCAL_URL").expect("LOCAL_URL is not set");
+async fn main() -> ExitCode {
+    let cli = Cli::parse();
 
-    let roomid = std::env::var("ROOMID")
-        .expect("ROOMID is not set")
-        .parse::<u32>()
-        .expect("Failed to parse roomid");
-
-    let ruid = std::env::var("RUID")
-        .expect("RUID is not set")
-        .parse::<u32>()
-        .expect("Failed to parse ruid");
+    dotenvy::dotenv().ok();
 
-    // initialize tracing
+    // initialize tracing, on stderr so it doesn't mix with what commands print
     let env_log = EnvFilter::try_from_default_env();
+    let layer = fmt::layer().with_writer(std::io::stderr);
 
     if let Ok(filter) = env_log {
         tracing_subscriber::registry()
-            .with(fmt::layer().with_filter(filter))
+            .with(layer.with_filter(filter))
             .init();
     } else {
-        tracing_subscriber::registry().with(fmt::layer()).init();
+        tracing_subscriber::registry().with(layer).init();
     }
 
-    let client = Client::builder()
-        .user_agent("Mozilla/5.0 (X11; Linux x86_64; rv:140.0) Gecko/20100101 Firefox/140.0")
-        .build()
-        .unwrap();
-
-    let app = Router::new()
-        .route("/", get(get_list))
-        .with_state(ShareState {
-            roomid,
-            ruid,
-            client: Arc::new(client),
-        });
+    let command = cli.command.unwrap_or(Command::Serve);
 
-    let listener = tokio::net::TcpListener::bind(local_url).await.unwrap();
-    axum::serve(listener, app).await.unwrap();
-}
-
-#[derive(Debug, Deserialize)]
-struct QueryUsername {
-    username: Option<String>,
-}
-
-async fn get_list(
-    State(ShareState {
-        roomid,
-        ruid,
-        client,
-    }): State<ShareState>,
-    Query(QueryUsername { username }): Query<QueryUsername>,
-) -> Result<impl IntoResponse, AnyhowError> {
-    let list = get_captains(roomid, ruid, &client).await?;
-
-    if let Some(username) = username {
-        let res = list
-            .into_iter()
-            .filter(|u| u.username.contains(&username))
-            .map(|u| u.username)
-            .collect::<Vec<_>>();
-
-        return Ok(res.join("\n"));
+    // comparing saved rosters needs no configuration
+    if let Command::Diff { from, to } = &command {
+        return exit_code(cli::diff(from, to));
     }
 
-    Ok(list
-        .into_iter()
-        .map(|u| u.username)
-        .collect::<Vec<_>>()
-        .join("\n"))
-}
-
-async fn get_captains(
-    roomid: u32,
-    ruid: u32,
-    client: &reqwest::Client,
-) -> Result<Vec<CaptainEntry>> {
-    let mut page = 1;
+    let loaded = Config::load().and_then(|config| match command {
+        Command::Serve => config.validate_serve().map(|()| config),
+        _ => Ok(config),
+    });
 
-    let mut res = vec![];
-
-    loop {
-        let resp = client
-            .get("https://api.live.bilibili.com/xlive/app-room/v2/guardTab/topList")
-            .query(&[
-                ("roomid", roomid.to_string()),
-                ("ruid", ruid.to_string()),
-                ("page", page.to_string()),
-                ("page_size", "30".to_string()),
-            ])
-            .send()
-            .await?
-            .error_for_status()?;
-
-        let c = resp.json::<Captain>().await?;
-
-        if c.data.info.page < page {
-            return Ok(res);
-        }
-
-        if let Some(top3) = c.data.top3
-            && page == 1
-        {
-            for i in top3 {
-                res.push(i);
-            }
+    let config = match loaded {
+        Ok(config) => config,
+        Err(e) => {
+            eprint!("{e}");
+            return ExitCode::from(2);
         }
+    };
 
-        let list = c.data.list;
-
-        for i in list {
-            res.push(i);
+    let http = reqwest::Client::builder()
+        .user_agent(USER_AGENT)
+        .connect_timeout(Duration::from_secs(config.upstream.connect_timeout_secs))
+        .read_timeout(Duration::from_secs(config.upstream.read_timeout_secs))
+        .build()
+        .unwrap();
+    let client = BiliLiveClient::with_http(http);
+
+    let result = match command {
+        Command::Serve => server::serve(config, client)
+            .await
+            .map(|()| ExitCode::SUCCESS),
+        Command::Dump { room, format, bom } => {
+            cli::dump(&config, &client, &room, format, bom).await
         }
+        Command::Diff { .. } => unreachable!("handled without a configuration"),
+        Command::Check {
+            room,
+            uid,
+            username,
+        } => cli::check(&config, &client, &room, uid, username.as_deref()).await,
+    };
+
+    exit_code(result)
+}
 
-        page += 1;
-    }
+/// Reports errors of commands on stderr, with the exit code for errors.
+fn exit_code(result: anyhow::Result<ExitCode>) -> ExitCode {
+    result.unwrap_or_else(|e| {
+        eprintln!("{e:#}");
+        ExitCode::from(2)
+    })
 }
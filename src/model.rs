@@ -0,0 +1,62 @@
+use serde::{Deserialize, Serialize};
+
+/// One guard as the topList API lists them.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct CaptainEntry {
+    pub uid: u64,
+    pub username: String,
+    pub rank: u32,
+    pub guard_level: GuardLevel,
+    /// Days the user has been accompanying the streamer as a guard.
+    #[serde(default)]
+    pub accompany: u32,
+    pub face: String,
+    pub medal_info: Option<MedalInfo>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct MedalInfo {
+    pub medal_name: String,
+    pub medal_level: u32,
+}
+
+/// Guard tier, numbered the same way Bilibili does: a lower number is a higher tier.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(try_from = "u8", into = "u8")]
+pub enum GuardLevel {
+    /// 总督
+    Governor = 1,
+    /// 提督
+    Admiral = 2,
+    /// 舰长
+    Captain = 3,
+}
+
+impl TryFrom<u8> for GuardLevel {
+    type Error = String;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            1 => Ok(Self::Governor),
+            2 => Ok(Self::Admiral),
+            3 => Ok(Self::Captain),
+            v => Err(format!("unknown guard level {v}")),
+        }
+    }
+}
+
+impl From<GuardLevel> for u8 {
+    fn from(value: GuardLevel) -> Self {
+        value as u8
+    }
+}
+
+impl GuardLevel {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Governor => "总督",
+            Self::Admiral => "提督",
+            Self::Captain => "舰长",
+        }
+    }
+}
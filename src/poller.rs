@@ -0,0 +1,26 @@
+use std::{sync::Arc, time::Duration};
+
+use mulike::retry::jitter;
+use tokio::task::JoinHandle;
+use tracing::{debug, warn};
+
+use crate::cache::RosterCache;
+
+/// Refreshes `cache` right away and then every `interval`, plus up to
+/// `max_jitter` so several instances don't hit the API in lockstep.
+pub fn spawn(cache: Arc<RosterCache>, interval: Duration, max_jitter: Duration) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        loop {
+            match cache.refresh().await {
+                Ok(snapshot) => debug!(
+                    "Polled room {}: {} captains",
+                    cache.roomid(),
+                    snapshot.entries.len()
+                ),
+                Err(e) => warn!("Polling room {} failed: {e}", cache.roomid()),
+            }
+
+            tokio::time::sleep(interval + jitter(max_jitter)).await;
+        }
+    })
+}
@@ -0,0 +1,153 @@
+//! Applies edits of the configuration without a restart.
+//!
+//! The configuration is reloaded on SIGHUP, and whenever the file changes.
+//! Rooms are compared with the running ones: those that stay keep running
+//! untouched, new ones are started and the rest are stopped. The served
+//! [`ShareState`] is then replaced in one go, so open connections are kept,
+//! except for event streams of stopped rooms, which end.
+//!
+//! Only `rooms` and `auth` are reloaded. Everything else keeps its running
+//! value until a restart. An invalid configuration, or a room that fails to
+//! start, leaves the running one in place.
+
+use std::{
+    path::Path,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{Context, Result};
+use mulike::BiliLiveClient;
+use tokio::signal::unix::{SignalKind, signal};
+use tracing::{error, info, warn};
+
+use crate::{
+    config::Config,
+    room::{Room, Rooms},
+    server::{AppState, ShareState},
+    webhook::Webhooks,
+};
+
+/// How often the file is checked for changes.
+const WATCH_INTERVAL: Duration = Duration::from_secs(5);
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+/// Reloads into `state` from now on, starting from the `running` configuration.
+pub fn spawn(
+    state: AppState,
+    mut running: Config,
+    client: BiliLiveClient,
+    webhooks: Option<Webhooks>,
+) -> Result<()> {
+    let mut hangup = signal(SignalKind::hangup()).context("Failed to listen for SIGHUP")?;
+    let (path, _) = Config::path();
+    let mut last_modified = modified(&path);
+
+    tokio::spawn(async move {
+        loop {
+            tokio::select! {
+                _ = hangup.recv() => info!("Received SIGHUP, reloading the configuration"),
+                _ = tokio::time::sleep(WATCH_INTERVAL) => {
+                    let now = modified(&path);
+
+                    if now == last_modified {
+                        continue;
+                    }
+
+                    last_modified = now;
+                    info!("{} changed, reloading the configuration", path.display());
+                }
+            }
+
+            if let Some(config) = reload(&state, &running, &client, webhooks.as_ref()).await {
+                running = config;
+            }
+        }
+    });
+
+    Ok(())
+}
+
+/// Returns the configuration now running, or `None` if it stayed the same.
+async fn reload(
+    state: &AppState,
+    running: &Config,
+    client: &BiliLiveClient,
+    webhooks: Option<&Webhooks>,
+) -> Option<Config> {
+    let loaded = Config::load().and_then(|config| config.validate_serve().map(|()| config));
+
+    let loaded = match loaded {
+        Ok(config) => config,
+        Err(e) => {
+            error!("Keeping the running configuration. {e}");
+            return None;
+        }
+    };
+
+    let config = Config {
+        rooms: loaded.rooms.clone(),
+        auth: loaded.auth.clone(),
+        ..running.clone()
+    };
+
+    if config != loaded {
+        warn!("Only rooms and auth are reloaded, restart to apply the other changes");
+    }
+
+    if config == *running {
+        return None;
+    }
+
+    let current = state.get();
+    let mut rooms = vec![];
+    let mut started = vec![];
+
+    for room in &config.rooms {
+        if let Some(room) = current.rooms.by_config(room) {
+            rooms.push(room.clone());
+            continue;
+        }
+
+        match Room::start(room, &config, client, current.store.as_ref(), webhooks).await {
+            Ok(room) => {
+                started.push(room.roomid());
+                rooms.push(room);
+            }
+            Err(e) => {
+                error!("Keeping the running configuration. {e}");
+
+                for room in &rooms {
+                    if current.rooms.by_config(&room.config).is_none() {
+                        room.stop();
+                    }
+                }
+
+                return None;
+            }
+        }
+    }
+
+    let rooms = Rooms::new(rooms);
+    let mut stopped = vec![];
+
+    for room in current.rooms.iter() {
+        if rooms.by_config(&room.config).is_none() {
+            room.stop();
+            stopped.push(room.roomid());
+        }
+    }
+
+    state.replace(ShareState {
+        rooms: Arc::new(rooms),
+        store: current.store,
+        tokens: config.tokens(),
+    });
+
+    info!("Reloaded the configuration, started rooms {started:?} and stopped rooms {stopped:?}");
+
+    Some(config)
+}
@@ -0,0 +1,88 @@
+use std::{
+    hash::{BuildHasher, Hasher, RandomState},
+    time::Duration,
+};
+
+use anyhow::Result;
+use tracing::warn;
+
+use crate::upstream::UpstreamError;
+
+/// A random duration in `0..=max`, without pulling in a RNG crate.
+pub fn jitter(max: Duration) -> Duration {
+    let random = RandomState::new().build_hasher().finish();
+    max.mul_f64(random as f64 / u64::MAX as f64)
+}
+
+/// How often and how patiently to retry a failed upstream request.
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    /// Retries after the first attempt; 0 disables retrying.
+    pub retries: u32,
+    /// Delay before the first retry, doubled on every further one.
+    pub base: Duration,
+    pub max: Duration,
+}
+
+impl RetryPolicy {
+    /// Runs `f` until it succeeds, fails with an error that retrying won't
+    /// fix, or runs out of retries.
+    pub async fn run<T, F, Fut>(&self, what: &str, mut f: F) -> Result<T>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        let mut attempt = 0;
+
+        loop {
+            match f().await {
+                Ok(v) => return Ok(v),
+                Err(e) if attempt < self.retries && is_transient(&e) => {
+                    // upstream may ask for hours, which would stall whoever waits on us
+                    let delay = retry_after(&e)
+                        .map(|delay| delay.min(self.max))
+                        .unwrap_or_else(|| self.backoff(attempt));
+                    attempt += 1;
+                    warn!(
+                        "{what} failed: {e}, retry {attempt}/{} in {delay:?}",
+                        self.retries
+                    );
+                    tokio::time::sleep(delay).await;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Exponential backoff with half of the delay randomised, capped at `max`.
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let delay = self
+            .base
+            .saturating_mul(2u32.saturating_pow(attempt))
+            .min(self.max);
+
+        delay / 2 + jitter(delay / 2)
+    }
+}
+
+fn is_transient(e: &anyhow::Error) -> bool {
+    if let Some(e) = e.downcast_ref::<UpstreamError>() {
+        return e.is_transient();
+    }
+
+    if let Some(e) = e.downcast_ref::<reqwest::Error>() {
+        return e.is_timeout()
+            || e.is_connect()
+            || e.is_body()
+            || e.status().is_some_and(|s| s.is_server_error());
+    }
+
+    false
+}
+
+fn retry_after(e: &anyhow::Error) -> Option<Duration> {
+    match e.downcast_ref::<UpstreamError>() {
+        Some(UpstreamError::RateLimited { retry_after, .. }) => *retry_after,
+        _ => None,
+    }
+}
@@ -0,0 +1,213 @@
+use std::{str::FromStr, sync::Arc, time::Duration};
+
+use anyhow::{Context, Result, bail};
+use mulike::{BiliLiveClient, RoomInfo};
+use serde::Deserialize;
+use tokio::task::JoinHandle;
+use tracing::warn;
+
+use crate::{
+    cache::RosterCache, config::Config, danmaku, events::EventLog, poller, store::Store,
+    webhook::Webhooks,
+};
+
+/// One entry of `ROOMS`, written as `[alias=]roomid[:ruid]`.
+///
+/// `roomid` may be a short room ID. `ruid` is looked up from the room when left out.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RoomConfig {
+    pub alias: Option<String>,
+    pub roomid: u32,
+    pub ruid: Option<u64>,
+}
+
+impl FromStr for RoomConfig {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (alias, ids) = match s.split_once('=') {
+            Some((alias, ids)) => (Some(alias.trim().to_string()), ids),
+            None => (None, s),
+        };
+
+        let (roomid, ruid) = match ids.split_once(':') {
+            Some((roomid, ruid)) => (roomid, Some(ruid)),
+            None => (ids, None),
+        };
+
+        Ok(Self {
+            alias,
+            roomid: roomid.trim().parse().context("Failed to parse roomid")?,
+            ruid: ruid
+                .map(|ruid| ruid.trim().parse().context("Failed to parse ruid"))
+                .transpose()?,
+        })
+    }
+}
+
+impl RoomConfig {
+    /// Looks up the canonical room ID and anchor of the room.
+    pub async fn resolve(&self, client: &BiliLiveClient) -> Result<RoomInfo> {
+        let roomid = self.roomid;
+
+        let info = match (client.room_info(roomid).await, self.ruid) {
+            (Ok(info), _) => info,
+            // with both IDs given we can still start, and serve stored snapshots
+            (Err(e), Some(ruid)) => {
+                warn!("Failed to resolve room {roomid}, using it as configured: {e}");
+                RoomInfo {
+                    roomid,
+                    short_id: None,
+                    ruid,
+                }
+            }
+            (Err(e), None) => bail!("Failed to resolve room {roomid}: {e}"),
+        };
+
+        // a mismatched RUID doesn't fail upstream, it just yields the wrong list
+        if let Some(ruid) = self.ruid
+            && ruid != info.ruid
+        {
+            bail!(
+                "RUID {ruid} does not match room {roomid}, whose anchor is {}",
+                info.ruid
+            );
+        }
+
+        Ok(info)
+    }
+}
+
+#[derive(Debug)]
+pub struct Room {
+    pub config: RoomConfig,
+    pub short_id: Option<u32>,
+    pub cache: Arc<RosterCache>,
+    pub events: Arc<EventLog>,
+    /// Poller and live message listener, aborted by [`Self::stop`].
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl Room {
+    /// Resolves the room, loads its last stored roster and starts keeping it
+    /// up to date as `settings` say.
+    pub async fn start(
+        config: &RoomConfig,
+        settings: &Config,
+        client: &BiliLiveClient,
+        store: Option<&Store>,
+        webhooks: Option<&Webhooks>,
+    ) -> Result<Arc<Self>> {
+        let info = config.resolve(client).await?;
+
+        let poll_interval = Duration::from_secs(settings.poll.interval_secs);
+
+        let mut cache = RosterCache::new(
+            info.roomid,
+            info.ruid,
+            client.clone(),
+            settings.crawl(),
+            Duration::from_secs(settings.cache.ttl_secs),
+            Duration::from_secs(settings.cache.stale_secs),
+        );
+
+        if !poll_interval.is_zero() {
+            cache = cache.polled();
+        }
+
+        if let Some(store) = store {
+            cache = cache.with_store(store.clone());
+        }
+
+        if let Err(e) = cache.seed().await {
+            warn!(
+                "Failed to load the last roster of room {}: {e}",
+                info.roomid
+            );
+        }
+
+        let cache = Arc::new(cache);
+        let events = EventLog::spawn(&cache);
+
+        // subscribe to roster changes before the poller makes any
+        if let Some(webhooks) = webhooks {
+            webhooks.watch(&cache);
+        }
+
+        let mut tasks = vec![];
+
+        if !poll_interval.is_zero() {
+            tasks.push(poller::spawn(
+                cache.clone(),
+                poll_interval,
+                Duration::from_secs(settings.poll.jitter_secs),
+            ));
+        }
+
+        if settings.upstream.live_events {
+            tasks.push(danmaku::spawn(
+                cache.clone(),
+                client.http().clone(),
+                settings.retry(),
+            ));
+        }
+
+        Ok(Arc::new(Self {
+            config: config.clone(),
+            short_id: info.short_id,
+            cache,
+            events,
+            tasks,
+        }))
+    }
+
+    /// Stops refreshing the roster. Whoever still holds the room can read
+    /// the last one.
+    pub fn stop(&self) {
+        for task in &self.tasks {
+            task.abort();
+        }
+    }
+
+    pub fn roomid(&self) -> u32 {
+        self.cache.roomid()
+    }
+}
+
+/// All served rooms. The first one is what `/` serves.
+#[derive(Debug)]
+pub struct Rooms(Vec<Arc<Room>>);
+
+impl Rooms {
+    pub fn new(rooms: Vec<Arc<Room>>) -> Self {
+        assert!(!rooms.is_empty(), "at least one room must be configured");
+        Self(rooms)
+    }
+
+    pub fn primary(&self) -> &Arc<Room> {
+        &self.0[0]
+    }
+
+    /// Looks a room up by its canonical or short ID.
+    pub fn by_id(&self, roomid: u32) -> Option<&Arc<Room>> {
+        self.0
+            .iter()
+            .find(|r| r.roomid() == roomid || r.short_id == Some(roomid))
+    }
+
+    pub fn by_alias(&self, alias: &str) -> Option<&Arc<Room>> {
+        self.0
+            .iter()
+            .find(|r| r.config.alias.as_deref() == Some(alias))
+    }
+
+    /// Looks up the room started from exactly `config`.
+    pub fn by_config(&self, config: &RoomConfig) -> Option<&Arc<Room>> {
+        self.0.iter().find(|r| r.config == *config)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Arc<Room>> {
+        self.0.iter()
+    }
+}
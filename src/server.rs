@@ -0,0 +1,621 @@
+use std::{
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Result};
+use axum::{
+    Json, Router,
+    extract::{FromRef, Path, Query, State, WebSocketUpgrade},
+    http::{HeaderMap, header},
+    response::{
+        IntoResponse, Response,
+        sse::{KeepAlive, Sse},
+    },
+    routing::get,
+};
+use mulike::{
+    Anomaly, BiliLiveClient, CaptainEntry, GuardLevel, MembershipClaims, RosterDiff, UpstreamError,
+    diff_rosters, issue_membership_token, verify_membership_token,
+};
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::sync::watch;
+use tracing::{error, warn};
+
+use crate::{
+    config::Config,
+    events,
+    export::Delimited,
+    filter::{MatchMode, UsernameFilter},
+    reload,
+    room::{Room, Rooms},
+    store::Store,
+    webhook, ws,
+};
+
+// learned from https://github.com/tokio-rs/axum/blob/main/examples/anyhow-error-response/src/main.rs
+pub struct AnyhowError(anyhow::Error);
+
+impl IntoResponse for AnyhowError {
+    fn into_response(self) -> Response {
+        if let Some(e) = self.0.downcast_ref::<UpstreamError>() {
+            warn!("Returning upstream error: {e}");
+            return e.into_response();
+        }
+
+        error!("Returning internal server error for {}", self.0);
+        (StatusCode::INTERNAL_SERVER_ERROR, format!("{}", self.0)).into_response()
+    }
+}
+
+impl<E> From<E> for AnyhowError
+where
+    E: Into<anyhow::Error>,
+{
+    fn from(err: E) -> Self {
+        Self(err.into())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ShareState {
+    pub rooms: Arc<Rooms>,
+    pub store: Option<Store>,
+    pub tokens: Option<TokenConfig>,
+}
+
+/// The [`ShareState`] being served, replaced as a whole by [`crate::reload`].
+///
+/// Handlers extract a copy of it, so a request sees one configuration
+/// throughout.
+#[derive(Debug, Clone)]
+pub struct AppState(Arc<watch::Sender<ShareState>>);
+
+impl AppState {
+    pub fn new(state: ShareState) -> Self {
+        Self(Arc::new(watch::Sender::new(state)))
+    }
+
+    pub fn get(&self) -> ShareState {
+        self.0.borrow().clone()
+    }
+
+    pub fn replace(&self, state: ShareState) {
+        self.0.send_replace(state);
+    }
+
+    pub fn subscribe(&self) -> watch::Receiver<ShareState> {
+        self.0.subscribe()
+    }
+}
+
+impl FromRef<AppState> for ShareState {
+    fn from_ref(state: &AppState) -> Self {
+        state.get()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TokenConfig {
+    pub secret: Arc<[u8]>,
+    /// What callers of `/token` authenticate with.
+    pub api_key: Arc<str>,
+    pub ttl: Duration,
+}
+
+/// Serves the configured rooms until the listeners fail.
+pub async fn serve(config: Config, client: BiliLiveClient) -> Result<()> {
+    let retention = Duration::from_secs(config.database.retention_days * 24 * 60 * 60);
+    let store = config
+        .database
+        .path
+        .as_ref()
+        .map(|path| Store::open(path, retention).context("Failed to open the snapshot database"))
+        .transpose()?;
+
+    // validation made sure there is a database for the outbox
+    let webhooks = config.webhooks().map(|webhooks| {
+        let store = store.clone().expect("webhooks need a database");
+        webhook::spawn(webhooks, store, client.http().clone())
+    });
+
+    let mut rooms = vec![];
+
+    for room in &config.rooms {
+        let room = Room::start(room, &config, &client, store.as_ref(), webhooks.as_ref()).await?;
+        rooms.push(room);
+    }
+
+    let state = AppState::new(ShareState {
+        rooms: Arc::new(Rooms::new(rooms)),
+        store,
+        tokens: config.tokens(),
+    });
+
+    reload::spawn(state.clone(), config.clone(), client, webhooks)?;
+
+    let app = Router::new()
+        .route("/", get(get_list))
+        .route("/rooms/{roomid}", get(get_room_list))
+        .route("/rooms/by-alias/{name}", get(get_alias_list))
+        .route("/diff", get(get_diff))
+        .route("/check", get(get_check))
+        .route("/token", get(get_token))
+        .route("/token/verify", get(get_token_verify))
+        .route("/events", get(get_events))
+        .route("/ws", get(get_ws))
+        .with_state(state);
+
+    let mut servers = vec![];
+
+    for address in &config.listen {
+        let listener = tokio::net::TcpListener::bind(address)
+            .await
+            .with_context(|| format!("Failed to listen on {address}"))?;
+        servers.push(tokio::spawn(
+            axum::serve(listener, app.clone()).into_future(),
+        ));
+    }
+
+    for server in servers {
+        server.await?.context("Failed to serve")?;
+    }
+
+    Ok(())
+}
+
+#[derive(Debug, Deserialize)]
+struct QueryUsername {
+    /// Comma-separated usernames, or a pattern with `match=regex`.
+    username: Option<String>,
+    #[serde(default, rename = "match")]
+    match_mode: MatchMode,
+    format: Option<String>,
+    /// Prefix CSV/TSV output with a UTF-8 byte order mark.
+    #[serde(default)]
+    bom: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum OutputFormat {
+    Text,
+    Json,
+    Delimited(Delimited),
+}
+
+impl OutputFormat {
+    /// `?format=` wins over the `Accept` header; anything unrecognised falls back to text.
+    fn negotiate(format: Option<&str>, headers: &HeaderMap) -> Self {
+        if let Some(format) = format {
+            return match format {
+                "json" => Self::Json,
+                "csv" => Self::Delimited(Delimited::Csv),
+                "tsv" => Self::Delimited(Delimited::Tsv),
+                _ => Self::Text,
+            };
+        }
+
+        let accepts_json = headers
+            .get_all(header::ACCEPT)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+            .any(|v| v.split(';').next().unwrap_or_default().trim() == "application/json");
+
+        if accepts_json { Self::Json } else { Self::Text }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct ListResponse {
+    pub roomid: u32,
+    pub total: usize,
+    /// Unix timestamp in seconds.
+    pub fetched_at: u64,
+    /// Oddities in the upstream pages of the crawl that produced `list`.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub anomalies: Vec<Anomaly>,
+    pub list: Vec<CaptainEntry>,
+}
+
+async fn get_list(
+    State(ShareState { rooms, .. }): State<ShareState>,
+    headers: HeaderMap,
+    Query(query): Query<QueryUsername>,
+) -> Result<Response, AnyhowError> {
+    render_list(rooms.primary(), &headers, query).await
+}
+
+async fn get_room_list(
+    State(ShareState { rooms, .. }): State<ShareState>,
+    Path(roomid): Path<u32>,
+    headers: HeaderMap,
+    Query(query): Query<QueryUsername>,
+) -> Result<Response, AnyhowError> {
+    match rooms.by_id(roomid) {
+        Some(room) => render_list(room, &headers, query).await,
+        None => Ok((StatusCode::NOT_FOUND, format!("Unknown room {roomid}")).into_response()),
+    }
+}
+
+async fn get_alias_list(
+    State(ShareState { rooms, .. }): State<ShareState>,
+    Path(name): Path<String>,
+    headers: HeaderMap,
+    Query(query): Query<QueryUsername>,
+) -> Result<Response, AnyhowError> {
+    match rooms.by_alias(&name) {
+        Some(room) => render_list(room, &headers, query).await,
+        None => Ok((StatusCode::NOT_FOUND, format!("Unknown room alias {name}")).into_response()),
+    }
+}
+
+async fn render_list(
+    room: &Room,
+    headers: &HeaderMap,
+    QueryUsername {
+        username,
+        match_mode,
+        format,
+        bom,
+    }: QueryUsername,
+) -> Result<Response, AnyhowError> {
+    let roomid = room.roomid();
+    let snapshot = room.cache.get().await?;
+    let fetched_at = snapshot.fetched_at.duration_since(UNIX_EPOCH)?.as_secs();
+    let mut list = snapshot.entries.clone();
+
+    if let Some(username) = username {
+        let filter = match UsernameFilter::new(match_mode, &username) {
+            Ok(filter) => filter,
+            Err(e) => return Ok((StatusCode::BAD_REQUEST, e.to_string()).into_response()),
+        };
+
+        filter.apply(&mut list);
+    }
+
+    match OutputFormat::negotiate(format.as_deref(), headers) {
+        OutputFormat::Json => Ok(Json(ListResponse {
+            roomid,
+            total: list.len(),
+            fetched_at,
+            anomalies: snapshot.report.anomalies.clone(),
+            list,
+        })
+        .into_response()),
+        OutputFormat::Delimited(d) => Ok((
+            [(header::CONTENT_TYPE, d.content_type())],
+            d.render(&list, bom),
+        )
+            .into_response()),
+        OutputFormat::Text => Ok(list
+            .into_iter()
+            .map(|u| u.username)
+            .collect::<Vec<_>>()
+            .join("\n")
+            .into_response()),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct QueryRoom {
+    roomid: Option<u32>,
+}
+
+/// Streams roster changes as server-sent events, resuming from `Last-Event-ID`.
+async fn get_events(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(QueryRoom { roomid }): Query<QueryRoom>,
+) -> Response {
+    // subscribed first, so a reload right after picking the room is seen
+    let reloads = state.subscribe();
+    let rooms = reloads.borrow().rooms.clone();
+
+    let room = match roomid {
+        Some(roomid) => match rooms.by_id(roomid) {
+            Some(room) => room,
+            None => {
+                return (StatusCode::NOT_FOUND, format!("Unknown room {roomid}")).into_response();
+            }
+        },
+        None => rooms.primary(),
+    };
+
+    let last_id = headers
+        .get("last-event-id")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.trim().parse().ok());
+
+    Sse::new(events::sse(room.clone(), last_id, reloads))
+        .keep_alive(KeepAlive::default())
+        .into_response()
+}
+
+async fn get_ws(State(state): State<AppState>, upgrade: WebSocketUpgrade) -> Response {
+    upgrade.on_upgrade(move |socket| ws::serve(socket, state.subscribe()))
+}
+
+#[derive(Debug, Deserialize)]
+struct QueryCheck {
+    uid: Option<u64>,
+    /// Matched exactly.
+    username: Option<String>,
+    roomid: Option<u32>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CheckResponse {
+    pub roomid: u32,
+    pub is_guard: bool,
+    #[serde(flatten)]
+    pub guard: Option<CheckedGuard>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CheckedGuard {
+    uid: u64,
+    username: String,
+    guard_level: GuardLevel,
+    guard_name: &'static str,
+    /// Unix timestamp in seconds. From the recorded roster history when
+    /// there is one, otherwise estimated from the accompany days.
+    since: u64,
+}
+
+/// Answers whether someone is a guard right now, with 200 if they are and 404 if not.
+async fn get_check(
+    State(ShareState { rooms, store, .. }): State<ShareState>,
+    Query(QueryCheck {
+        uid,
+        username,
+        roomid,
+    }): Query<QueryCheck>,
+) -> Result<Response, AnyhowError> {
+    let room = match roomid {
+        Some(roomid) => match rooms.by_id(roomid) {
+            Some(room) => room,
+            None => {
+                return Ok(
+                    (StatusCode::NOT_FOUND, format!("Unknown room {roomid}")).into_response()
+                );
+            }
+        },
+        None => rooms.primary(),
+    };
+
+    if uid.is_none() && username.is_none() {
+        return Ok((
+            StatusCode::BAD_REQUEST,
+            "Either uid or username is required",
+        )
+            .into_response());
+    }
+
+    let snapshot = room.cache.get().await?;
+
+    let Some(entry) = snapshot.entries.iter().find(|e| {
+        uid.is_none_or(|uid| e.uid == uid)
+            && username
+                .as_deref()
+                .is_none_or(|username| e.username == username)
+    }) else {
+        return Ok((
+            StatusCode::NOT_FOUND,
+            Json(CheckResponse {
+                roomid: room.roomid(),
+                is_guard: false,
+                guard: None,
+            }),
+        )
+            .into_response());
+    };
+
+    Ok(Json(CheckResponse {
+        roomid: room.roomid(),
+        is_guard: true,
+        guard: Some(checked_guard(room.roomid(), entry, store.as_ref()).await?),
+    })
+    .into_response())
+}
+
+pub async fn checked_guard(
+    roomid: u32,
+    entry: &CaptainEntry,
+    store: Option<&Store>,
+) -> Result<CheckedGuard> {
+    let recorded = match store {
+        Some(store) => store.guard_since(roomid, entry.uid).await?,
+        None => None,
+    };
+    let since = recorded.unwrap_or_else(|| {
+        SystemTime::now() - Duration::from_secs(u64::from(entry.accompany) * 24 * 60 * 60)
+    });
+
+    Ok(CheckedGuard {
+        uid: entry.uid,
+        username: entry.username.clone(),
+        guard_level: entry.guard_level,
+        guard_name: entry.guard_level.name(),
+        since: since.duration_since(UNIX_EPOCH)?.as_secs(),
+    })
+}
+
+#[derive(Debug, Deserialize)]
+struct QueryToken {
+    uid: u64,
+    roomid: Option<u32>,
+}
+
+#[derive(Debug, Serialize)]
+struct TokenResponse {
+    token: String,
+    /// Unix timestamp in seconds.
+    expires_at: u64,
+}
+
+/// Whether `headers` carry `Authorization: Bearer <api_key>`.
+fn authorized(headers: &HeaderMap, api_key: &str) -> bool {
+    let Some(key) = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+    else {
+        return false;
+    };
+
+    // compare digests so the time taken doesn't tell how much of the key matched
+    Sha256::digest(key.trim()) == Sha256::digest(api_key)
+}
+
+/// Issues a membership token for `uid`, if they are a guard right now.
+///
+/// Anyone could ask for anyone's token here, so only trusted services that
+/// authenticated the user themselves may call it, with the API key.
+async fn get_token(
+    State(ShareState { rooms, tokens, .. }): State<ShareState>,
+    headers: HeaderMap,
+    Query(QueryToken { uid, roomid }): Query<QueryToken>,
+) -> Result<Response, AnyhowError> {
+    let Some(tokens) = tokens else {
+        return Ok((
+            StatusCode::SERVICE_UNAVAILABLE,
+            "Membership tokens are disabled, set TOKEN_SECRET to enable them",
+        )
+            .into_response());
+    };
+
+    if !authorized(&headers, &tokens.api_key) {
+        return Ok((
+            StatusCode::UNAUTHORIZED,
+            [(header::WWW_AUTHENTICATE, "Bearer")],
+            "Send the API key as a bearer token",
+        )
+            .into_response());
+    }
+
+    let room = match roomid {
+        Some(roomid) => match rooms.by_id(roomid) {
+            Some(room) => room,
+            None => {
+                return Ok(
+                    (StatusCode::NOT_FOUND, format!("Unknown room {roomid}")).into_response()
+                );
+            }
+        },
+        None => rooms.primary(),
+    };
+
+    let snapshot = room.cache.get().await?;
+
+    let Some(entry) = snapshot.entries.iter().find(|e| e.uid == uid) else {
+        return Ok((
+            StatusCode::NOT_FOUND,
+            format!("{uid} is not a guard of room {}", room.roomid()),
+        )
+            .into_response());
+    };
+
+    let claims = MembershipClaims::new(uid, room.roomid(), entry.guard_level, tokens.ttl);
+
+    Ok(Json(TokenResponse {
+        token: issue_membership_token(&claims, &tokens.secret),
+        expires_at: claims.exp,
+    })
+    .into_response())
+}
+
+#[derive(Debug, Deserialize)]
+struct QueryVerify {
+    token: String,
+}
+
+/// Returns the claims of a valid token, or 401.
+async fn get_token_verify(
+    State(ShareState { tokens, .. }): State<ShareState>,
+    Query(QueryVerify { token }): Query<QueryVerify>,
+) -> Response {
+    let Some(tokens) = tokens else {
+        return (
+            StatusCode::SERVICE_UNAVAILABLE,
+            "Membership tokens are disabled, set TOKEN_SECRET to enable them",
+        )
+            .into_response();
+    };
+
+    match verify_membership_token(&token, &tokens.secret) {
+        Ok(claims) => Json(claims).into_response(),
+        Err(e) => (StatusCode::UNAUTHORIZED, e.to_string()).into_response(),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct QueryDiff {
+    /// Unix timestamp in seconds.
+    since: u64,
+    roomid: Option<u32>,
+}
+
+#[derive(Debug, Serialize)]
+struct DiffResponse {
+    roomid: u32,
+    /// Unix timestamps in seconds of the compared snapshots.
+    from: u64,
+    to: u64,
+    #[serde(flatten)]
+    diff: RosterDiff,
+}
+
+/// Compares the roster as recorded at `since` with the current one.
+async fn get_diff(
+    State(ShareState { rooms, store, .. }): State<ShareState>,
+    Query(QueryDiff { since, roomid }): Query<QueryDiff>,
+) -> Result<Response, AnyhowError> {
+    let room = match roomid {
+        Some(roomid) => match rooms.by_id(roomid) {
+            Some(room) => room,
+            None => {
+                return Ok(
+                    (StatusCode::NOT_FOUND, format!("Unknown room {roomid}")).into_response()
+                );
+            }
+        },
+        None => rooms.primary(),
+    };
+
+    let Some(store) = store else {
+        return Ok((
+            StatusCode::SERVICE_UNAVAILABLE,
+            "Roster history is disabled, set DATABASE_PATH to enable it",
+        )
+            .into_response());
+    };
+
+    let Some(at) = UNIX_EPOCH.checked_add(Duration::from_secs(since)) else {
+        return Ok((
+            StatusCode::BAD_REQUEST,
+            format!("since {since} is out of range"),
+        )
+            .into_response());
+    };
+
+    let Some(old) = store.latest(room.roomid(), Some(at)).await? else {
+        return Ok((
+            StatusCode::NOT_FOUND,
+            format!("No roster of room {} recorded at {since}", room.roomid()),
+        )
+            .into_response());
+    };
+
+    let new = room.cache.get().await?;
+
+    Ok(Json(DiffResponse {
+        roomid: room.roomid(),
+        from: old.fetched_at.duration_since(UNIX_EPOCH)?.as_secs(),
+        to: new.fetched_at.duration_since(UNIX_EPOCH)?.as_secs(),
+        diff: diff_rosters(&old.entries, &new.entries),
+    })
+    .into_response())
+}
@@ -0,0 +1,283 @@
+use std::{
+    path::Path,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Result;
+use rusqlite::{Connection, OptionalExtension, params};
+
+use mulike::CaptainEntry;
+
+use crate::cache::Snapshot;
+
+const SCHEMA: &str = "
+PRAGMA foreign_keys = ON;
+PRAGMA journal_mode = WAL;
+
+CREATE TABLE IF NOT EXISTS snapshots (
+    id INTEGER PRIMARY KEY,
+    roomid INTEGER NOT NULL,
+    fetched_at INTEGER NOT NULL,
+    -- last crawl that found exactly this roster
+    checked_at INTEGER NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS snapshots_room_time ON snapshots (roomid, fetched_at);
+
+CREATE TABLE IF NOT EXISTS snapshot_entries (
+    snapshot_id INTEGER NOT NULL REFERENCES snapshots (id) ON DELETE CASCADE,
+    roomid INTEGER NOT NULL,
+    uid INTEGER NOT NULL,
+    position INTEGER NOT NULL,
+    username TEXT NOT NULL,
+    guard_level INTEGER NOT NULL,
+    entry TEXT NOT NULL,
+    PRIMARY KEY (snapshot_id, uid)
+);
+
+CREATE INDEX IF NOT EXISTS snapshot_entries_room_uid ON snapshot_entries (roomid, uid);
+
+CREATE TABLE IF NOT EXISTS webhook_outbox (
+    id INTEGER PRIMARY KEY,
+    url TEXT NOT NULL,
+    payload TEXT NOT NULL,
+    attempts INTEGER NOT NULL DEFAULT 0,
+    next_attempt_at INTEGER NOT NULL
+);
+";
+
+/// A webhook delivery that hasn't succeeded yet.
+#[derive(Debug, Clone)]
+pub struct OutboxItem {
+    pub id: i64,
+    pub url: String,
+    pub payload: String,
+    pub attempts: u32,
+}
+
+/// Roster history in an SQLite database.
+///
+/// A crawl that finds the same roster as the latest stored snapshot only
+/// bumps its `checked_at`, so an idle room doesn't grow the database.
+/// Snapshots not checked within `retention` are deleted as new ones come in.
+#[derive(Debug, Clone)]
+pub struct Store {
+    conn: Arc<Mutex<Connection>>,
+    retention: Duration,
+}
+
+fn unix(time: SystemTime) -> i64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs() as i64
+}
+
+fn from_unix(secs: i64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_secs(secs.max(0) as u64)
+}
+
+impl Store {
+    pub fn open(path: impl AsRef<Path>, retention: Duration) -> Result<Self> {
+        let conn = Connection::open(path)?;
+        conn.execute_batch(SCHEMA)?;
+
+        Ok(Self {
+            conn: Arc::new(Mutex::new(conn)),
+            retention,
+        })
+    }
+
+    async fn with_conn<T, F>(&self, f: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Connection) -> Result<T> + Send + 'static,
+    {
+        let conn = self.conn.clone();
+        tokio::task::spawn_blocking(move || f(&mut conn.lock().unwrap())).await?
+    }
+
+    /// Records `snapshot`. `unchanged` means it has the same roster as the
+    /// latest stored one, which then only gets its check time bumped.
+    pub async fn save(&self, roomid: u32, snapshot: Arc<Snapshot>, unchanged: bool) -> Result<()> {
+        let expired = unix(
+            snapshot
+                .fetched_at
+                .checked_sub(self.retention)
+                .unwrap_or(UNIX_EPOCH),
+        );
+
+        self.with_conn(move |conn| {
+            let tx = conn.transaction()?;
+            let fetched_at = unix(snapshot.fetched_at);
+
+            let touched = unchanged
+                && tx.execute(
+                    "UPDATE snapshots SET checked_at = ?1
+                     WHERE id = (SELECT MAX(id) FROM snapshots WHERE roomid = ?2)",
+                    params![fetched_at, roomid],
+                )? > 0;
+
+            if !touched {
+                tx.execute(
+                    "INSERT INTO snapshots (roomid, fetched_at, checked_at) VALUES (?1, ?2, ?2)",
+                    params![roomid, fetched_at],
+                )?;
+                let id = tx.last_insert_rowid();
+
+                let mut insert = tx.prepare(
+                    "INSERT OR IGNORE INTO snapshot_entries
+                     (snapshot_id, roomid, uid, position, username, guard_level, entry)
+                     VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+                )?;
+
+                for (position, entry) in snapshot.entries.iter().enumerate() {
+                    insert.execute(params![
+                        id,
+                        roomid,
+                        entry.uid as i64,
+                        position as i64,
+                        entry.username,
+                        u8::from(entry.guard_level),
+                        serde_json::to_string(entry)?,
+                    ])?;
+                }
+            }
+
+            // keep at least the newest snapshot of each room
+            tx.execute(
+                "DELETE FROM snapshots
+                 WHERE checked_at < ?1
+                 AND id NOT IN (SELECT MAX(id) FROM snapshots GROUP BY roomid)",
+                params![expired],
+            )?;
+
+            tx.commit()?;
+            Ok(())
+        })
+        .await
+    }
+
+    /// The newest snapshot of `roomid` fetched at or before `at`, or the
+    /// newest one overall.
+    pub async fn latest(&self, roomid: u32, at: Option<SystemTime>) -> Result<Option<Snapshot>> {
+        let at = at.map_or(i64::MAX, unix);
+
+        self.with_conn(move |conn| {
+            let Some((id, checked_at)) = conn
+                .query_row(
+                    "SELECT id, checked_at FROM snapshots
+                     WHERE roomid = ?1 AND fetched_at <= ?2
+                     ORDER BY fetched_at DESC, id DESC LIMIT 1",
+                    params![roomid, at],
+                    |row| Ok((row.get::<_, i64>(0)?, row.get::<_, i64>(1)?)),
+                )
+                .optional()?
+            else {
+                return Ok(None);
+            };
+
+            let entries = conn
+                .prepare(
+                    "SELECT entry FROM snapshot_entries WHERE snapshot_id = ?1 ORDER BY position",
+                )?
+                .query_map(params![id], |row| row.get::<_, String>(0))?
+                .map(|entry| Ok(serde_json::from_str::<CaptainEntry>(&entry?)?))
+                .collect::<Result<Vec<_>>>()?;
+
+            Ok(Some(Snapshot::new(entries, from_unix(checked_at))))
+        })
+        .await
+    }
+
+    /// Queues `payload` for delivery to every one of `urls`.
+    pub async fn enqueue_webhooks(&self, urls: Vec<String>, payload: String) -> Result<()> {
+        let now = unix(SystemTime::now());
+
+        self.with_conn(move |conn| {
+            let tx = conn.transaction()?;
+
+            for url in urls {
+                tx.execute(
+                    "INSERT INTO webhook_outbox (url, payload, next_attempt_at) VALUES (?1, ?2, ?3)",
+                    params![url, payload, now],
+                )?;
+            }
+
+            tx.commit()?;
+            Ok(())
+        })
+        .await
+    }
+
+    /// Deliveries whose next attempt is due, oldest first.
+    pub async fn due_webhooks(&self, limit: u32) -> Result<Vec<OutboxItem>> {
+        let now = unix(SystemTime::now());
+
+        self.with_conn(move |conn| {
+            Ok(conn
+                .prepare(
+                    "SELECT id, url, payload, attempts FROM webhook_outbox
+                     WHERE next_attempt_at <= ?1 ORDER BY id LIMIT ?2",
+                )?
+                .query_map(params![now, limit], |row| {
+                    Ok(OutboxItem {
+                        id: row.get(0)?,
+                        url: row.get(1)?,
+                        payload: row.get(2)?,
+                        attempts: row.get(3)?,
+                    })
+                })?
+                .collect::<rusqlite::Result<Vec<_>>>()?)
+        })
+        .await
+    }
+
+    /// Removes a delivery from the outbox, after it succeeded or was given up on.
+    pub async fn remove_webhook(&self, id: i64) -> Result<()> {
+        self.with_conn(move |conn| {
+            conn.execute("DELETE FROM webhook_outbox WHERE id = ?1", params![id])?;
+            Ok(())
+        })
+        .await
+    }
+
+    pub async fn reschedule_webhook(&self, id: i64, next_attempt_at: SystemTime) -> Result<()> {
+        let next_attempt_at = unix(next_attempt_at);
+
+        self.with_conn(move |conn| {
+            conn.execute(
+                "UPDATE webhook_outbox SET attempts = attempts + 1, next_attempt_at = ?2
+                 WHERE id = ?1",
+                params![id, next_attempt_at],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    /// When `uid` was first recorded in the run of snapshots of `roomid`
+    /// that continues to the latest one, i.e. since when they are a guard
+    /// as far as the recorded history goes.
+    pub async fn guard_since(&self, roomid: u32, uid: u64) -> Result<Option<SystemTime>> {
+        self.with_conn(move |conn| {
+            let since = conn.query_row(
+                "SELECT MIN(s.fetched_at) FROM snapshots s
+                 JOIN snapshot_entries e ON e.snapshot_id = s.id
+                 WHERE s.roomid = ?1 AND e.uid = ?2 AND s.fetched_at > COALESCE(
+                     (SELECT MAX(a.fetched_at) FROM snapshots a
+                      WHERE a.roomid = ?1 AND NOT EXISTS (
+                          SELECT 1 FROM snapshot_entries ae
+                          WHERE ae.snapshot_id = a.id AND ae.uid = ?2
+                      )),
+                     -1
+                 )",
+                params![roomid, uid as i64],
+                |row| row.get::<_, Option<i64>>(0),
+            )?;
+
+            Ok(since.map(from_unix))
+        })
+        .await
+    }
+}
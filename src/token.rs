@@ -0,0 +1,219 @@
+//! Short-lived membership tokens, so other services can check someone's
+//! guard status without asking us.
+//!
+//! Tokens are JWTs signed with HMAC-SHA256 (`HS256`), verifiable by any JWT
+//! library given the shared secret, or by [`verify_membership_token`].
+
+use std::{
+    fmt,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+
+use crate::GuardLevel;
+
+const ISSUER: &str = "mulike";
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Header {
+    alg: String,
+    typ: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MembershipClaims {
+    pub iss: String,
+    /// The UID as a string, as JWT wants it.
+    pub sub: String,
+    pub uid: u64,
+    pub roomid: u32,
+    pub guard_level: GuardLevel,
+    /// Unix timestamps in seconds.
+    pub iat: u64,
+    pub exp: u64,
+}
+
+impl MembershipClaims {
+    pub fn new(uid: u64, roomid: u32, guard_level: GuardLevel, ttl: Duration) -> Self {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+
+        Self {
+            iss: ISSUER.to_string(),
+            sub: uid.to_string(),
+            uid,
+            roomid,
+            guard_level,
+            iat: now,
+            exp: now + ttl.as_secs(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TokenError {
+    Malformed,
+    UnsupportedAlgorithm,
+    BadSignature,
+    Expired,
+}
+
+impl fmt::Display for TokenError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Malformed => "malformed token",
+            Self::UnsupportedAlgorithm => "unsupported signing algorithm",
+            Self::BadSignature => "bad signature",
+            Self::Expired => "token expired",
+        })
+    }
+}
+
+impl std::error::Error for TokenError {}
+
+fn mac(secret: &[u8], signed: &str) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("HMAC takes keys of any size");
+    mac.update(signed.as_bytes());
+    mac
+}
+
+fn encode_json(value: &impl Serialize) -> String {
+    URL_SAFE_NO_PAD.encode(serde_json::to_vec(value).expect("claims always serialize"))
+}
+
+fn decode_json<T: for<'de> Deserialize<'de>>(part: &str) -> Result<T, TokenError> {
+    let json = URL_SAFE_NO_PAD
+        .decode(part)
+        .map_err(|_| TokenError::Malformed)?;
+    serde_json::from_slice(&json).map_err(|_| TokenError::Malformed)
+}
+
+pub fn issue_membership_token(claims: &MembershipClaims, secret: &[u8]) -> String {
+    let header = Header {
+        alg: "HS256".to_string(),
+        typ: "JWT".to_string(),
+    };
+    let signed = format!("{}.{}", encode_json(&header), encode_json(claims));
+    let signature = URL_SAFE_NO_PAD.encode(mac(secret, &signed).finalize().into_bytes());
+
+    format!("{signed}.{signature}")
+}
+
+/// Checks the signature and expiry of a token from [`issue_membership_token`].
+pub fn verify_membership_token(token: &str, secret: &[u8]) -> Result<MembershipClaims, TokenError> {
+    let (signed, signature) = token.rsplit_once('.').ok_or(TokenError::Malformed)?;
+    let (header, claims) = signed.split_once('.').ok_or(TokenError::Malformed)?;
+
+    let header = decode_json::<Header>(header)?;
+    if header.alg != "HS256" {
+        return Err(TokenError::UnsupportedAlgorithm);
+    }
+
+    let signature = URL_SAFE_NO_PAD
+        .decode(signature)
+        .map_err(|_| TokenError::Malformed)?;
+    mac(secret, signed)
+        .verify_slice(&signature)
+        .map_err(|_| TokenError::BadSignature)?;
+
+    let claims = decode_json::<MembershipClaims>(claims)?;
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    if claims.exp <= now {
+        return Err(TokenError::Expired);
+    }
+
+    Ok(claims)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &[u8] = b"0123456789abcdef";
+
+    fn claims(ttl: Duration) -> MembershipClaims {
+        MembershipClaims::new(42, 1234, GuardLevel::Admiral, ttl)
+    }
+
+    #[test]
+    fn verifies_issued_tokens() {
+        let claims = claims(Duration::from_secs(600));
+        let token = issue_membership_token(&claims, SECRET);
+
+        assert_eq!(verify_membership_token(&token, SECRET), Ok(claims));
+    }
+
+    #[test]
+    fn rejects_other_secrets() {
+        let token = issue_membership_token(&claims(Duration::from_secs(600)), SECRET);
+
+        assert_eq!(
+            verify_membership_token(&token, b"fedcba9876543210"),
+            Err(TokenError::BadSignature)
+        );
+    }
+
+    #[test]
+    fn rejects_tampered_claims() {
+        let token = issue_membership_token(&claims(Duration::from_secs(600)), SECRET);
+        let forged = encode_json(&MembershipClaims {
+            guard_level: GuardLevel::Governor,
+            ..claims(Duration::from_secs(600))
+        });
+        let mut parts = token.split('.').collect::<Vec<_>>();
+        parts[1] = &forged;
+
+        assert_eq!(
+            verify_membership_token(&parts.join("."), SECRET),
+            Err(TokenError::BadSignature)
+        );
+    }
+
+    #[test]
+    fn rejects_expired_tokens() {
+        let token = issue_membership_token(&claims(Duration::ZERO), SECRET);
+
+        assert_eq!(
+            verify_membership_token(&token, SECRET),
+            Err(TokenError::Expired)
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_tokens() {
+        for token in ["", "a", "a.b", "a.b.c", "!.!.!"] {
+            assert_eq!(
+                verify_membership_token(token, SECRET),
+                Err(TokenError::Malformed),
+                "{token:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_other_algorithms() {
+        let header = encode_json(&Header {
+            alg: "none".to_string(),
+            typ: "JWT".to_string(),
+        });
+        let token = format!(
+            "{header}.{}.",
+            encode_json(&claims(Duration::from_secs(600)))
+        );
+
+        assert_eq!(
+            verify_membership_token(&token, SECRET),
+            Err(TokenError::UnsupportedAlgorithm)
+        );
+    }
+}
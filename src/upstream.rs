@@ -0,0 +1,121 @@
+use std::{fmt, time::Duration};
+
+use axum::{
+    Json,
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+use serde::Serialize;
+
+/// A non-zero `code` in a Bilibili API response.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UpstreamError {
+    /// -352: the request was caught by risk control (风控).
+    RiskControl {
+        message: String,
+    },
+    /// -412, or HTTP 412: too many requests from this address.
+    RateLimited {
+        message: String,
+        /// From `Retry-After`, when upstream sent one.
+        retry_after: Option<Duration>,
+    },
+    /// The room does not exist or was given with invalid parameters.
+    InvalidRoom {
+        code: i32,
+        message: String,
+    },
+    Other {
+        code: i32,
+        message: String,
+    },
+}
+
+impl UpstreamError {
+    pub fn new(code: i32, message: String) -> Self {
+        match code {
+            -352 => Self::RiskControl { message },
+            -412 => Self::RateLimited {
+                message,
+                retry_after: None,
+            },
+            -400 | 1 | 60004 | 19002000 => Self::InvalidRoom { code, message },
+            code => Self::Other { code, message },
+        }
+    }
+
+    pub fn code(&self) -> i32 {
+        match self {
+            Self::RiskControl { .. } => -352,
+            Self::RateLimited { .. } => -412,
+            Self::InvalidRoom { code, .. } | Self::Other { code, .. } => *code,
+        }
+    }
+
+    pub fn message(&self) -> &str {
+        match self {
+            Self::RiskControl { message }
+            | Self::RateLimited { message, .. }
+            | Self::InvalidRoom { message, .. }
+            | Self::Other { message, .. } => message,
+        }
+    }
+
+    fn kind(&self) -> &'static str {
+        match self {
+            Self::RiskControl { .. } => "risk_control",
+            Self::RateLimited { .. } => "rate_limited",
+            Self::InvalidRoom { .. } => "invalid_room",
+            Self::Other { .. } => "upstream",
+        }
+    }
+
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Self::RiskControl { .. } => StatusCode::SERVICE_UNAVAILABLE,
+            Self::RateLimited { .. } => StatusCode::TOO_MANY_REQUESTS,
+            Self::InvalidRoom { .. } => StatusCode::NOT_FOUND,
+            Self::Other { .. } => StatusCode::BAD_GATEWAY,
+        }
+    }
+
+    /// Whether asking again later may succeed.
+    pub fn is_transient(&self) -> bool {
+        matches!(self, Self::RiskControl { .. } | Self::RateLimited { .. })
+    }
+}
+
+impl fmt::Display for UpstreamError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Bilibili API returned {} ({}): {}",
+            self.kind(),
+            self.code(),
+            self.message()
+        )
+    }
+}
+
+impl std::error::Error for UpstreamError {}
+
+#[derive(Debug, Serialize)]
+struct ErrorBody<'a> {
+    error: &'static str,
+    code: i32,
+    message: &'a str,
+}
+
+impl IntoResponse for &UpstreamError {
+    fn into_response(self) -> Response {
+        (
+            self.status(),
+            Json(ErrorBody {
+                error: self.kind(),
+                code: self.code(),
+                message: self.message(),
+            }),
+        )
+            .into_response()
+    }
+}
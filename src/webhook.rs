@@ -0,0 +1,176 @@
+use std::{
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Result;
+use hmac::{Hmac, Mac};
+use serde::Serialize;
+use sha2::Sha256;
+use tokio::sync::{Notify, broadcast::error::RecvError};
+use tracing::{debug, error, warn};
+
+use mulike::{RosterDiff, retry::RetryPolicy};
+
+use crate::{
+    cache::{RosterCache, RosterUpdate},
+    store::{OutboxItem, Store},
+};
+
+/// Hex HMAC-SHA256 of the body, keyed with `WEBHOOK_SECRET`.
+const SIGNATURE_HEADER: &str = "X-Mulike-Signature";
+
+#[derive(Debug, Clone)]
+pub struct WebhookConfig {
+    pub urls: Vec<String>,
+    pub secret: Option<String>,
+    /// `retries` is the number of attempts after the first one before a
+    /// delivery is dropped.
+    pub retry: RetryPolicy,
+}
+
+#[derive(Debug, Serialize)]
+struct RosterChanged<'a> {
+    event: &'static str,
+    roomid: u32,
+    /// Unix timestamp in seconds of the crawl that found the change.
+    at: u64,
+    #[serde(flatten)]
+    diff: &'a RosterDiff,
+}
+
+fn sign(secret: &str, payload: &str) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any size");
+    mac.update(payload.as_bytes());
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+/// Queues `roster.changed` events of the rooms it [watches](Self::watch)
+/// into the outbox.
+#[derive(Debug, Clone)]
+pub struct Webhooks {
+    urls: Vec<String>,
+    store: Store,
+    notify: Arc<Notify>,
+}
+
+impl Webhooks {
+    /// Queues a `roster.changed` event for every change of `cache`, until it is dropped.
+    pub fn watch(&self, cache: &RosterCache) {
+        let mut updates = cache.subscribe();
+        let roomid = cache.roomid();
+        let webhooks = self.clone();
+
+        tokio::spawn(async move {
+            loop {
+                match updates.recv().await {
+                    Ok(update) => {
+                        match enqueue(&webhooks.store, webhooks.urls.clone(), &update).await {
+                            Ok(()) => webhooks.notify.notify_one(),
+                            Err(e) => error!("Failed to queue webhooks for room {roomid}: {e}"),
+                        }
+                    }
+                    Err(RecvError::Lagged(n)) => {
+                        warn!("Webhooks of room {roomid} skipped {n} roster changes")
+                    }
+                    Err(RecvError::Closed) => return,
+                }
+            }
+        });
+    }
+}
+
+/// Delivers the events in the outbox of `store`, so they survive a restart.
+/// Rooms are added with [`Webhooks::watch`].
+pub fn spawn(config: WebhookConfig, store: Store, client: reqwest::Client) -> Webhooks {
+    let webhooks = Webhooks {
+        urls: config.urls.clone(),
+        store: store.clone(),
+        notify: Arc::new(Notify::new()),
+    };
+    let notify = webhooks.notify.clone();
+
+    tokio::spawn(async move {
+        loop {
+            match store.due_webhooks(32).await {
+                Ok(items) => {
+                    for item in items {
+                        deliver(&config, &store, &client, item).await;
+                    }
+                }
+                Err(e) => error!("Failed to read the webhook outbox: {e}"),
+            }
+
+            // wake up for new events right away, and for retries every now and then
+            tokio::select! {
+                _ = notify.notified() => {}
+                _ = tokio::time::sleep(Duration::from_secs(5)) => {}
+            }
+        }
+    });
+
+    webhooks
+}
+
+async fn enqueue(store: &Store, urls: Vec<String>, update: &RosterUpdate) -> Result<()> {
+    let payload = serde_json::to_string(&RosterChanged {
+        event: "roster.changed",
+        roomid: update.roomid,
+        at: update.at.duration_since(UNIX_EPOCH)?.as_secs(),
+        diff: &update.diff,
+    })?;
+
+    store.enqueue_webhooks(urls, payload).await
+}
+
+async fn deliver(
+    config: &WebhookConfig,
+    store: &Store,
+    client: &reqwest::Client,
+    item: OutboxItem,
+) {
+    let mut request = client
+        .post(&item.url)
+        .header(reqwest::header::CONTENT_TYPE, "application/json");
+
+    if let Some(secret) = &config.secret {
+        request = request.header(SIGNATURE_HEADER, sign(secret, &item.payload));
+    }
+
+    let result = request
+        .body(item.payload)
+        .send()
+        .await
+        .and_then(|resp| resp.error_for_status());
+
+    let stored = match result {
+        Ok(_) => {
+            debug!("Delivered webhook {} to {}", item.id, item.url);
+            store.remove_webhook(item.id).await
+        }
+        Err(e) if item.attempts >= config.retry.retries => {
+            error!(
+                "Giving up on webhook {} to {} after {} attempts: {e}",
+                item.id,
+                item.url,
+                item.attempts + 1
+            );
+            store.remove_webhook(item.id).await
+        }
+        Err(e) => {
+            let delay = config.retry.backoff(item.attempts);
+            warn!(
+                "Webhook {} to {} failed, retrying in {delay:?}: {e}",
+                item.id, item.url
+            );
+            store
+                .reschedule_webhook(item.id, SystemTime::now() + delay)
+                .await
+        }
+    };
+
+    if let Err(e) = stored {
+        error!("Failed to update the webhook outbox: {e}");
+    }
+}
@@ -0,0 +1,315 @@
+use std::{collections::HashMap, sync::Arc};
+
+use anyhow::Result;
+use axum::extract::ws::{Message, WebSocket};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    sync::{
+        broadcast::error::RecvError,
+        mpsc::{self, Sender},
+        watch,
+    },
+    task::JoinHandle,
+};
+use tracing::debug;
+
+use mulike::CaptainEntry;
+
+use crate::{
+    events::{LoggedEvent, RosterEvent},
+    filter::{MatchMode, UsernameFilter},
+    room::Room,
+    server::ShareState,
+};
+
+/// What clients send, e.g. `{"type": "subscribe", "rooms": [123]}`.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Command {
+    Subscribe {
+        rooms: Vec<u32>,
+    },
+    Unsubscribe {
+        rooms: Vec<u32>,
+    },
+    /// Only pass on entries and events whose username matches `username`,
+    /// compared as `match` says, like `?match=` does; `null` removes the filter.
+    Filter {
+        username: Option<String>,
+        #[serde(default, rename = "match")]
+        match_mode: MatchMode,
+    },
+    /// Sends the full roster of `roomid` again, or of every subscribed room.
+    Resync {
+        roomid: Option<u32>,
+    },
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Reply<'a> {
+    Snapshot {
+        roomid: u32,
+        /// Events up to this ID are already reflected in `entries`.
+        last_id: u64,
+        entries: Vec<&'a CaptainEntry>,
+    },
+    Event {
+        event: &'a LoggedEvent,
+    },
+    Error {
+        message: String,
+    },
+}
+
+struct Connection {
+    state: watch::Receiver<ShareState>,
+    subscriptions: HashMap<u32, (Arc<Room>, JoinHandle<()>)>,
+    filter: Option<UsernameFilter>,
+    events: Sender<Arc<LoggedEvent>>,
+}
+
+impl Connection {
+    fn matches(&self, username: &str) -> bool {
+        self.filter
+            .as_ref()
+            .is_none_or(|filter| filter.matches(username))
+    }
+
+    fn event_matches(&self, event: &RosterEvent) -> bool {
+        match event {
+            RosterEvent::Join { entry } | RosterEvent::Leave { entry } => {
+                self.matches(&entry.username)
+            }
+            RosterEvent::Rename { from, to, .. } => self.matches(from) || self.matches(to),
+            RosterEvent::TierChange { username, .. } => self.matches(username),
+            RosterEvent::Resync => true,
+        }
+    }
+
+    async fn send(&self, socket: &mut WebSocket, reply: &Reply<'_>) -> Result<()> {
+        let text = serde_json::to_string(reply)?;
+        socket.send(Message::Text(text.into())).await?;
+        Ok(())
+    }
+
+    async fn send_error(&self, socket: &mut WebSocket, message: String) -> Result<()> {
+        self.send(socket, &Reply::Error { message }).await
+    }
+
+    async fn send_snapshot(&self, socket: &mut WebSocket, room: &Room) -> Result<()> {
+        let last_id = room.events.last_id();
+
+        let snapshot = match room.cache.get().await {
+            Ok(snapshot) => snapshot,
+            Err(e) => {
+                return self
+                    .send_error(
+                        socket,
+                        format!("Failed to fetch room {}: {e}", room.roomid()),
+                    )
+                    .await;
+            }
+        };
+
+        let entries = snapshot
+            .entries
+            .iter()
+            .filter(|e| self.matches(&e.username))
+            .collect();
+
+        self.send(
+            socket,
+            &Reply::Snapshot {
+                roomid: room.roomid(),
+                last_id,
+                entries,
+            },
+        )
+        .await
+    }
+
+    async fn subscribe(&mut self, socket: &mut WebSocket, roomid: u32) -> Result<()> {
+        let room = self.state.borrow().rooms.by_id(roomid).cloned();
+
+        let Some(room) = room else {
+            return self
+                .send_error(socket, format!("Unknown room {roomid}"))
+                .await;
+        };
+
+        if self.subscriptions.contains_key(&room.roomid()) {
+            return Ok(());
+        }
+
+        // subscribe before taking the snapshot, so nothing falls in between
+        let (_, mut rx) = room.events.subscribe(None);
+        let events = self.events.clone();
+        let roomid = room.roomid();
+
+        let forward = tokio::spawn(async move {
+            loop {
+                let event = match rx.recv().await {
+                    Ok(event) => event,
+                    // the connection turns this into a fresh snapshot
+                    Err(RecvError::Lagged(_)) => Arc::new(LoggedEvent {
+                        id: 0,
+                        roomid,
+                        event: RosterEvent::Resync,
+                    }),
+                    Err(RecvError::Closed) => return,
+                };
+
+                if events.send(event).await.is_err() {
+                    return;
+                }
+            }
+        });
+
+        self.subscriptions
+            .insert(room.roomid(), (room.clone(), forward));
+        self.send_snapshot(socket, &room).await
+    }
+
+    fn unsubscribe(&mut self, roomid: u32) {
+        if let Some((_, forward)) = self.subscriptions.remove(&roomid) {
+            forward.abort();
+        }
+    }
+
+    /// Moves subscriptions over to the rooms a reload started in their
+    /// place, and drops those to rooms it stopped serving.
+    async fn reload(&mut self, socket: &mut WebSocket) -> Result<()> {
+        let changed = {
+            let state = self.state.borrow();
+
+            self.subscriptions
+                .iter()
+                .filter_map(|(&roomid, (room, _))| match state.rooms.by_id(roomid) {
+                    Some(current) if Arc::ptr_eq(current, room) => None,
+                    current => Some((roomid, current.is_some())),
+                })
+                .collect::<Vec<_>>()
+        };
+
+        for (roomid, served) in changed {
+            self.unsubscribe(roomid);
+
+            if served {
+                self.subscribe(socket, roomid).await?;
+            } else {
+                self.send_error(socket, format!("Room {roomid} is no longer served"))
+                    .await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn command(&mut self, socket: &mut WebSocket, text: &str) -> Result<()> {
+        let command = match serde_json::from_str::<Command>(text) {
+            Ok(command) => command,
+            Err(e) => {
+                return self
+                    .send_error(socket, format!("Invalid command: {e}"))
+                    .await;
+            }
+        };
+
+        match command {
+            Command::Subscribe { rooms } => {
+                for roomid in rooms {
+                    self.subscribe(socket, roomid).await?;
+                }
+            }
+            Command::Unsubscribe { rooms } => {
+                for roomid in rooms {
+                    self.unsubscribe(roomid);
+                }
+            }
+            Command::Filter {
+                username,
+                match_mode,
+            } => match username
+                .map(|u| UsernameFilter::new(match_mode, &u))
+                .transpose()
+            {
+                Ok(filter) => self.filter = filter,
+                Err(e) => {
+                    return self
+                        .send_error(socket, format!("Invalid filter: {e}"))
+                        .await;
+                }
+            },
+            Command::Resync { roomid } => {
+                let rooms = self
+                    .subscriptions
+                    .values()
+                    .map(|(room, _)| room.clone())
+                    .filter(|room| roomid.is_none_or(|id| id == room.roomid()))
+                    .collect::<Vec<_>>();
+
+                for room in rooms {
+                    self.send_snapshot(socket, &room).await?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn event(&self, socket: &mut WebSocket, event: &LoggedEvent) -> Result<()> {
+        let Some((room, _)) = self.subscriptions.get(&event.roomid) else {
+            // from a room that was unsubscribed in the meantime
+            return Ok(());
+        };
+
+        if matches!(event.event, RosterEvent::Resync) {
+            return self.send_snapshot(socket, room).await;
+        }
+
+        if !self.event_matches(&event.event) {
+            return Ok(());
+        }
+
+        self.send(socket, &Reply::Event { event }).await
+    }
+}
+
+/// Serves one `/ws` connection until either side closes it.
+///
+/// Rooms are looked up in the current `state`, so rooms added by a reload
+/// can be subscribed to without reconnecting.
+pub async fn serve(mut socket: WebSocket, state: watch::Receiver<ShareState>) {
+    let (events, mut rx) = mpsc::channel(64);
+    let mut reloads = state.clone();
+
+    let mut connection = Connection {
+        state,
+        subscriptions: HashMap::new(),
+        filter: None,
+        events,
+    };
+
+    loop {
+        let result = tokio::select! {
+            message = socket.recv() => match message {
+                Some(Ok(Message::Text(text))) => connection.command(&mut socket, text.as_str()).await,
+                Some(Ok(Message::Close(_))) | None => break,
+                Some(Ok(_)) => Ok(()),
+                Some(Err(e)) => Err(e.into()),
+            },
+            Some(event) = rx.recv() => connection.event(&mut socket, &event).await,
+            Ok(()) = reloads.changed() => connection.reload(&mut socket).await,
+        };
+
+        if let Err(e) = result {
+            debug!("Closing WebSocket connection: {e}");
+            break;
+        }
+    }
+
+    for (_, (_, forward)) in connection.subscriptions {
+        forward.abort();
+    }
+}